@@ -1,18 +1,55 @@
-use super::blockchain::HttpBlockchainReader;
+use super::blockchain::BlockchainReader;
 use super::Loan;
-use ethabi::Uint;
-use ethabi::{Address, Contract, Token};
-use std::error::Error;
+use super::amount::Amount;
+use super::decode::{parse_address, uint};
+use super::error::Result;
+use super::multicall::Batch;
+use ethabi::{Address, Contract, Token, Uint};
+use serde::Serialize;
 
-const AAVE_ADDRESS: &str = "7d2768dE32b0b80b7a3454c06BdAc94A69DDc7A9";
-pub struct Aave<'a> {
-    blockchain_reader: &'a HttpBlockchainReader,
+pub(crate) const AAVE_ADDRESS: &str = "7d2768dE32b0b80b7a3454c06BdAc94A69DDc7A9";
+
+/// Everything `getUserAccountData` tells about an account. Values are in eth, ratios are
+/// fractions of 1 (0.825 for a 82.5% liquidation threshold).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AaveAccount {
+    pub total_collateral: Amount,
+    pub total_debt: Amount,
+    pub available_borrows: Amount,
+    pub liquidation_threshold: f64,
+    pub ltv: f64,
+    /// None when there is no debt.
+    pub health_factor: Option<f64>,
+}
+
+impl AaveAccount {
+    pub fn loan(&self) -> Loan {
+        Loan::from_amounts(&self.total_collateral, &self.total_debt)
+    }
+
+    /// The eth price at which the account gets liquidated, for the short leg: stablecoin
+    /// collateral and eth debt, so the health factor falls as the eth price rises.
+    pub fn liquidation_price(&self, eth_price: f64) -> Option<f64> {
+        self.health_factor.map(|health_factor| eth_price * health_factor)
+    }
+
+    /// How much the eth price can rise before liquidation, 0.25 for +25%.
+    pub fn distance_to_liquidation(&self) -> Option<f64> {
+        self.health_factor.map(|health_factor| health_factor - 1.0)
+    }
+}
+pub struct Aave<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
     aave_address: Address,
     aave_contract: Contract,
 }
-impl<'a> Aave<'a> {
-    pub fn new(blockchain_reader: &'a HttpBlockchainReader ) -> Result<Self, Box<dyn Error>> {
-        let aave_address: Address = AAVE_ADDRESS.parse()?;
+impl<'a, R: BlockchainReader> Aave<'a, R> {
+    pub fn new(blockchain_reader: &'a R) -> Result<Self> {
+        Self::at(blockchain_reader, parse_address(AAVE_ADDRESS)?)
+    }
+
+    /// The lending pool deployed at `aave_address`.
+    pub fn at(blockchain_reader: &'a R, aave_address: Address) -> Result<Self> {
         let aave_abi: &[u8] = include_bytes!("abi/aave.abi");
         let aave_contract: Contract = Contract::load(aave_abi)?;
         Ok(Self {
@@ -22,31 +59,123 @@ impl<'a> Aave<'a> {
         })
     }
 
-    pub async fn get_loan(&self, address: &str) -> Result<Loan, Box<dyn Error>> {
-         let tokens = self
+    pub async fn get_account(&self, address: &str) -> Result<AaveAccount> {
+        let tokens = self
             .blockchain_reader
             .call_function(
                 &self.aave_contract,
                 &self.aave_address,
                 "getUserAccountData",
-                &[Token::Address(address.parse()?)],
+                &[Token::Address(parse_address(address)?)],
                 )
             .await?;
+        self.decode_account(&tokens)
+    }
+
+    /// Queue the `getUserAccountData` of `address` in `batch`, its output is read by `decode_account`.
+    pub fn add_account<'b>(&'b self, batch: &mut Batch<'b>, address: &str) -> Result<usize> {
+        batch.add(
+            &self.aave_contract,
+            &self.aave_address,
+            "getUserAccountData",
+            &[Token::Address(parse_address(address)?)],
+        )
+    }
+
+    pub fn decode_account(&self, tokens: &[Token]) -> Result<AaveAccount> {
+        // percentages have 4 decimals, the health factor 18
+        let ratio = |value: Uint| Amount::new(value, 4).to_f64();
+        let health_factor = uint(tokens, 5)?;
+        let health_factor = match health_factor == Uint::MAX {
+            true => None,
+            false => Some(Amount::from_wei(health_factor).to_f64()),
+        };
+
+        Ok(AaveAccount {
+            total_collateral: Amount::from_wei(uint(tokens, 0)?),
+            total_debt: Amount::from_wei(uint(tokens, 1)?),
+            available_borrows: Amount::from_wei(uint(tokens, 2)?),
+            liquidation_threshold: ratio(uint(tokens, 3)?),
+            ltv: ratio(uint(tokens, 4)?),
+            health_factor,
+        })
+    }
 
-        let col = tokens[0].clone().into_uint();
-        let col = col.unwrap();
-        let col = (col.as_u128() as f64) / Uint::exp10(18).as_u128() as f64;
+    /// The fee of a flash loan, 0.0009 for 0.09%.
+    pub async fn get_flash_loan_premium(&self) -> Result<f64> {
+        let tokens = self
+            .blockchain_reader
+            .call_function(&self.aave_contract, &self.aave_address, "FLASHLOAN_PREMIUM_TOTAL", &[])
+            .await?;
+        self.decode_flash_loan_premium(&tokens)
+    }
 
-        let debt = tokens[1].clone().into_uint();
-        let debt = debt.unwrap();
-        let debt = (debt.as_u128() as f64) / Uint::exp10(18).as_u128() as f64;
+    /// Queue `FLASHLOAN_PREMIUM_TOTAL` in `batch`, its output is read by `decode_flash_loan_premium`.
+    pub fn add_flash_loan_premium<'b>(&'b self, batch: &mut Batch<'b>) -> Result<usize> {
+        batch.add(&self.aave_contract, &self.aave_address, "FLASHLOAN_PREMIUM_TOTAL", &[])
+    }
 
-        Ok(Loan{collateral:col, debt})
+    pub fn decode_flash_loan_premium(&self, tokens: &[Token]) -> Result<f64> {
+        // in basis points
+        Ok(Amount::new(uint(tokens, 0)?, 4).to_f64())
+    }
 
-   }
+    pub async fn get_loan(&self, address: &str) -> Result<Loan> {
+        let account = self.get_account(address).await?;
+        Ok(account.loan())
+    }
 
-    pub async fn get_eth_value(&self, address: &str) -> Result<f64, Box<dyn Error>> {
+    pub async fn get_eth_value(&self, address: &str) -> Result<f64> {
         let loan = self.get_loan(address).await?;
         Ok(loan.collateral-loan.debt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+
+    #[tokio::test]
+    async fn get_loan_decodes_user_account_data() {
+        let owner = "0000000000000000000000000000000000000001";
+        let contract = Contract::load(&include_bytes!("abi/aave.abi")[..]).unwrap();
+        let mut reader = MockBlockchainReader::new();
+        let account_data: Vec<Token> = vec![
+            Token::Uint(Uint::exp10(18) * 30),
+            Token::Uint(Uint::exp10(18) * 20),
+            Token::Uint(Uint::zero()),
+            Token::Uint(Uint::from(8500)),
+            Token::Uint(Uint::from(8000)),
+            Token::Uint(Uint::exp10(15) * 1275),
+        ];
+        reader
+            .mock_call(
+                &contract,
+                &AAVE_ADDRESS.parse().unwrap(),
+                "getUserAccountData",
+                &[Token::Address(owner.parse().unwrap())],
+                &account_data,
+            )
+            .unwrap();
+        let aave = Aave::new(&reader).unwrap();
+
+        let loan = aave.get_loan(owner).await.unwrap();
+        assert_eq!(loan.collateral, 30.0);
+        assert_eq!(loan.debt, 20.0);
+        assert_eq!(aave.get_eth_value(owner).await.unwrap(), 10.0);
+
+        let account = aave.get_account(owner).await.unwrap();
+        assert_eq!(account.liquidation_threshold, 0.85);
+        assert_eq!(account.ltv, 0.8);
+        assert_eq!(account.health_factor, Some(1.275));
+        assert_eq!(account.liquidation_price(2000.0), Some(2550.0));
+        assert_eq!(account.distance_to_liquidation().map(|d| (d * 1000.0).round()), Some(275.0));
+
+        reader
+            .mock_call(&contract, &AAVE_ADDRESS.parse().unwrap(), "FLASHLOAN_PREMIUM_TOTAL", &[], &[Token::Uint(Uint::from(9))])
+            .unwrap();
+        let aave = Aave::new(&reader).unwrap();
+        assert_eq!(aave.get_flash_loan_premium().await.unwrap(), 0.0009);
+    }
+}
@@ -0,0 +1,688 @@
+use super::error::{BermudaError, Result};
+use super::portfolio::Portfolio;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// One hour between two notifications of the same alert.
+const DEFAULT_COOLDOWN: u64 = 3600;
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A condition checked on every refresh of the portfolio.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AlertRule {
+    /// The eth price went past the rebalance down or up price of the previous refresh.
+    RebalancePrice,
+    /// The health factor of a leg fell below `min`.
+    HealthFactor { min: f64 },
+    /// The short / long value ratio left `[min, max]`.
+    LegRatio { min: f64, max: f64 },
+    /// The ETH / USD answer is older than `max_age` seconds, or was rejected by the oracle limits.
+    StaleOracle { max_age: u64 },
+}
+
+/// Where the alerts are sent.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum NotifierConfig {
+    /// POST the alert as JSON to an `http://` url.
+    Webhook { url: String },
+    /// Send a mail through a SMTP relay that needs neither TLS nor authentication.
+    Smtp { server: String, from: String, to: Vec<String> },
+    /// Run a program with the alert in the `BERMUDA_ALERT` and `BERMUDA_MESSAGE` variables.
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// The `alerts` of a profile.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Seconds before an alert that still holds is sent again.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    pub rules: Vec<AlertRule>,
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+fn default_cooldown() -> u64 {
+    DEFAULT_COOLDOWN
+}
+
+/// A rule that matched. Alerts with the same `key` are the same alert.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub key: String,
+    pub message: String,
+}
+
+impl Alert {
+    fn new(key: &str, message: String) -> Self {
+        Self {
+            key: key.to_string(),
+            message,
+        }
+    }
+}
+
+/// The alerts of `rules` for the portfolio. `previous_rebalance` are the rebalance down and up
+/// prices of the previous refresh, which the price is compared to.
+pub fn check_rules(rules: &[AlertRule], portfolio: &Portfolio, previous_rebalance: Option<(f64, f64)>) -> Vec<Alert> {
+    let snapshot = &portfolio.snapshot;
+    let price = snapshot.eth_price;
+    let mut alerts = Vec::new();
+    for rule in rules {
+        match *rule {
+            AlertRule::RebalancePrice => match previous_rebalance {
+                Some((down, _)) if down > 0.0 && price <= down => alerts.push(Alert::new(
+                    "rebalance_down",
+                    format!("eth price {:.2} $ went below the rebalance price {:.2} $", price, down),
+                )),
+                Some((_, up)) if up > 0.0 && price >= up => alerts.push(Alert::new(
+                    "rebalance_up",
+                    format!("eth price {:.2} $ went above the rebalance price {:.2} $", price, up),
+                )),
+                _ => {}
+            },
+            AlertRule::HealthFactor { min } => {
+                let legs = [
+                    ("short_health_factor", "Short (AAVE)", snapshot.short_account.health_factor),
+                    ("long_health_factor", "Long (Compound)", snapshot.long_account.health_factor()),
+                ];
+                for (key, name, health_factor) in legs.iter() {
+                    match health_factor {
+                        Some(health_factor) if *health_factor < min => alerts.push(Alert::new(
+                            key,
+                            format!("{} health factor {:.2} is below {:.2}", name, health_factor, min),
+                        )),
+                        _ => {}
+                    }
+                }
+            }
+            AlertRule::LegRatio { min, max } => {
+                let ratio = snapshot.short / snapshot.long;
+                if !(min..=max).contains(&ratio) {
+                    alerts.push(Alert::new(
+                        "leg_ratio",
+                        format!("short / long is {:.2}, outside of [{:.2}, {:.2}]", ratio, min, max),
+                    ));
+                }
+            }
+            AlertRule::StaleOracle { max_age } => {
+                if portfolio.eth_reading.age > max_age {
+                    alerts.push(Alert::new(
+                        "stale_oracle",
+                        format!("the eth price was updated {}s ago", portfolio.eth_reading.age),
+                    ));
+                }
+            }
+        }
+    }
+    alerts
+}
+
+/// The alert for a portfolio that could not be loaded because of its oracle, when watched.
+pub fn check_error(rules: &[AlertRule], error: &BermudaError) -> Option<Alert> {
+    let watched = rules.iter().any(|rule| matches!(rule, AlertRule::StaleOracle { .. }));
+    match error {
+        BermudaError::StaleOracle { .. } | BermudaError::OracleOutOfRange { .. } if watched => {
+            Some(Alert::new("stale_oracle", error.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Sends the alerts without blocking the watch, which runs on the same task.
+#[async_trait(?Send)]
+pub trait Notifier {
+    async fn notify(&self, alert: &Alert) -> Result<()>;
+}
+
+/// POSTs `{"key": ..., "message": ...}` to a plain http url.
+pub struct WebhookNotifier {
+    address: String,
+    host: String,
+    path: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            BermudaError::Config(format!(
+                "webhook {} must be an http:// url, use a command notifier with curl for https",
+                url
+            ))
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let address = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:80", host),
+        };
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        let body = serde_json::to_string(alert)?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        );
+        let response = timed(&self.address, async {
+            let mut stream = connect(&self.address).await?;
+            stream.write_all(request.as_bytes()).await.map_err(notify_error)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.map_err(notify_error)?;
+            Ok(response)
+        })
+        .await?;
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        match status.starts_with('2') {
+            true => Ok(()),
+            false => Err(BermudaError::Notify(format!(
+                "webhook answered {}",
+                response.lines().next().unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+/// Sends plain text mails through a SMTP relay.
+pub struct SmtpNotifier {
+    server: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    pub fn new(server: &str, from: &str, to: &[String]) -> Self {
+        Self {
+            server: server.to_string(),
+            from: from.to_string(),
+            to: to.to_vec(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Notifier for SmtpNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        timed(&self.server, self.send(alert)).await
+    }
+}
+
+impl SmtpNotifier {
+    async fn send(&self, alert: &Alert) -> Result<()> {
+        let mut stream = connect(&self.server).await?;
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        smtp_reply(&mut reader, "220").await?;
+        smtp_command(&mut writer, &mut reader, "EHLO bermuda", "250").await?;
+        smtp_command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", self.from), "250").await?;
+        for to in self.to.iter() {
+            smtp_command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", to), "250").await?;
+        }
+        smtp_command(&mut writer, &mut reader, "DATA", "354").await?;
+        // a leading dot would end the data early, so it is doubled
+        let body: Vec<String> = alert
+            .message
+            .lines()
+            .map(|line| match line.starts_with('.') {
+                true => format!(".{}", line),
+                false => line.to_string(),
+            })
+            .collect();
+        let data = format!(
+            "From: {}\r\nTo: {}\r\nSubject: bermuda alert: {}\r\n\r\n{}\r\n.",
+            self.from,
+            self.to.join(", "),
+            alert.key,
+            body.join("\r\n")
+        );
+        smtp_command(&mut writer, &mut reader, &data, "250").await?;
+        smtp_command(&mut writer, &mut reader, "QUIT", "221").await
+    }
+}
+
+/// Sends one SMTP command, failing unless its reply code is `expected`.
+async fn smtp_command<W: AsyncWrite + Unpin, R: AsyncBufRead + Unpin>(writer: &mut W, reader: &mut R, line: &str, expected: &str) -> Result<()> {
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await.map_err(notify_error)?;
+    smtp_reply(reader, expected).await
+}
+
+/// Reads a possibly multiline SMTP reply, failing unless its code is `expected`.
+async fn smtp_reply<R: AsyncBufRead + Unpin>(reader: &mut R, expected: &str) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.map_err(notify_error)?;
+        if line.is_empty() {
+            return Err(BermudaError::Notify(String::from("the SMTP server closed the connection")));
+        }
+        if !line.starts_with(expected) {
+            return Err(BermudaError::Notify(format!("the SMTP server answered {}", line.trim_end())));
+        }
+        // `250-` continues the reply, `250 ` ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs a program for each alert.
+pub struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandNotifier {
+    pub fn new(program: &str, args: &[String]) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.to_vec(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Notifier for CommandNotifier {
+    async fn notify(&self, alert: &Alert) -> Result<()> {
+        let status = Command::new(&self.program)
+            .args(&self.args)
+            .env("BERMUDA_ALERT", &alert.key)
+            .env("BERMUDA_MESSAGE", &alert.message)
+            .status()
+            .await
+            .map_err(|error| BermudaError::Notify(format!("cannot run {}: {}", self.program, error)))?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(BermudaError::Notify(format!("{} exited with {}", self.program, status))),
+        }
+    }
+}
+
+async fn connect(address: &str) -> Result<TcpStream> {
+    TcpStream::connect(address)
+        .await
+        .map_err(|error| BermudaError::Notify(format!("cannot connect to {}: {}", address, error)))
+}
+
+/// Fails the exchange with `address` when it takes longer than `NETWORK_TIMEOUT`.
+async fn timed<T>(address: &str, exchange: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(NETWORK_TIMEOUT, exchange)
+        .await
+        .map_err(|_| BermudaError::Notify(format!("{} did not answer in {}s", address, NETWORK_TIMEOUT.as_secs())))?
+}
+
+fn notify_error(error: std::io::Error) -> BermudaError {
+    BermudaError::Notify(error.to_string())
+}
+
+/// Checks the rules on each refresh and notifies the alerts, once per cooldown while they hold.
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    notifiers: Vec<Box<dyn Notifier>>,
+    cooldown: u64,
+    /// When each alert that still holds was last sent.
+    sent: HashMap<String, u64>,
+    previous_rebalance: Option<(f64, f64)>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>, notifiers: Vec<Box<dyn Notifier>>, cooldown: u64) -> Self {
+        Self {
+            rules,
+            notifiers,
+            cooldown,
+            sent: HashMap::new(),
+            previous_rebalance: None,
+        }
+    }
+
+    pub fn from_config(config: &AlertConfig) -> Result<Self> {
+        let notifiers = config
+            .notifiers
+            .iter()
+            .map(|notifier| -> Result<Box<dyn Notifier>> {
+                Ok(match notifier {
+                    NotifierConfig::Webhook { url } => Box::new(WebhookNotifier::new(url)?),
+                    NotifierConfig::Smtp { server, from, to } => Box::new(SmtpNotifier::new(server, from, to)),
+                    NotifierConfig::Command { program, args } => Box::new(CommandNotifier::new(program, args)),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::new(config.rules.clone(), notifiers, config.cooldown))
+    }
+
+    /// Check the rules on a refresh made at the unix time `now`, and notify the alerts that are
+    /// new or whose cooldown is over. Returns the alerts sent by at least one notifier, and the
+    /// failures of the notifiers.
+    pub async fn refresh(&mut self, portfolio: std::result::Result<&Portfolio, &BermudaError>, now: u64) -> (Vec<Alert>, Vec<BermudaError>) {
+        let alerts = match portfolio {
+            Ok(portfolio) => {
+                let alerts = check_rules(&self.rules, portfolio, self.previous_rebalance);
+                let snapshot = &portfolio.snapshot;
+                self.previous_rebalance = Some((snapshot.rebalance_down, snapshot.rebalance_up));
+                alerts
+            }
+            Err(error) => check_error(&self.rules, error).into_iter().collect(),
+        };
+        // an alert that stopped holding is sent again as soon as it comes back
+        self.sent.retain(|key, _| alerts.iter().any(|alert| &alert.key == key));
+
+        let mut notified = Vec::new();
+        let mut failures = Vec::new();
+        for alert in alerts {
+            let due = match self.sent.get(&alert.key) {
+                Some(sent) => now >= sent + self.cooldown,
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+            let mut delivered = false;
+            for notifier in self.notifiers.iter() {
+                match notifier.notify(&alert).await {
+                    Ok(()) => delivered = true,
+                    Err(error) => failures.push(error),
+                }
+            }
+            // an alert that no notifier could send is tried again on the next refresh
+            if delivered {
+                self.sent.insert(alert.key.clone(), now);
+                notified.push(alert);
+            }
+        }
+        (notified, failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portfolio::PortfolioSnapshot;
+    use crate::{rebalance_costs, AaveAccount, Amount, CometAccount, Currency, Equalize, LiquidationPrices, PriceReading, StrategyParams};
+    use ethabi::Uint;
+    use test_case::test_case;
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::thread;
+
+    struct Recorder(Rc<RefCell<Vec<Alert>>>);
+
+    #[async_trait(?Send)]
+    impl Notifier for Recorder {
+        async fn notify(&self, alert: &Alert) -> Result<()> {
+            self.0.borrow_mut().push(alert.clone());
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait(?Send)]
+    impl Notifier for Failing {
+        async fn notify(&self, _: &Alert) -> Result<()> {
+            Err(BermudaError::Notify(String::from("unreachable")))
+        }
+    }
+
+    fn stale(age: u64) -> BermudaError {
+        BermudaError::StaleOracle {
+            feed: ethabi::Address::zero(),
+            reason: format!("updated {}s ago", age),
+        }
+    }
+
+    #[tokio::test]
+    async fn engine_deduplicates_until_the_cooldown() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let rules = vec![AlertRule::StaleOracle { max_age: 3600 }];
+        let mut engine = AlertEngine::new(rules, vec![Box::new(Recorder(sent.clone()))], 600);
+
+        assert_eq!(engine.refresh(Err(&stale(4000)), 0).await.0.len(), 1);
+        assert_eq!(engine.refresh(Err(&stale(4060)), 60).await.0.len(), 0);
+        assert_eq!(engine.refresh(Err(&stale(4600)), 600).await.0.len(), 1);
+        // cleared by a refresh without the alert, then sent again right away
+        assert_eq!(engine.refresh(Err(&BermudaError::Input(String::new())), 660).await.0.len(), 0);
+        assert_eq!(engine.refresh(Err(&stale(3700)), 720).await.0.len(), 1);
+        assert_eq!(sent.borrow().len(), 3);
+        assert_eq!(sent.borrow()[0].key, "stale_oracle");
+    }
+
+    #[tokio::test]
+    async fn engine_retries_alerts_no_notifier_sent() {
+        let rules = vec![AlertRule::StaleOracle { max_age: 3600 }];
+        let mut engine = AlertEngine::new(rules.clone(), vec![Box::new(Failing)], 600);
+        let (alerts, failures) = engine.refresh(Err(&stale(4000)), 0).await;
+        assert!(alerts.is_empty());
+        assert_eq!(failures.len(), 1);
+        // not in cooldown, tried again right away
+        assert_eq!(engine.refresh(Err(&stale(4060)), 60).await.1.len(), 1);
+
+        // one notifier out of two is enough
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(Failing), Box::new(Recorder(sent.clone()))];
+        let mut engine = AlertEngine::new(rules, notifiers, 600);
+        let (alerts, failures) = engine.refresh(Err(&stale(4000)), 0).await;
+        assert_eq!((alerts.len(), failures.len()), (1, 1));
+        assert_eq!(engine.refresh(Err(&stale(4060)), 60).await, (Vec::new(), Vec::new()));
+        assert_eq!(sent.borrow().len(), 1);
+    }
+
+    /// A portfolio at 2000 $ with `short` and `long` in eth, the health factors of the legs
+    /// (None without debt) and the eth price read `age` seconds before the block.
+    fn portfolio(short: f64, long: f64, health_factors: (Option<f64>, Option<f64>), age: u64) -> Portfolio {
+        let zero = Amount::new(Uint::zero(), 18);
+        // the long health factor is 1 eth * 2000 $ * 0.9 over the debt
+        let long_debt = health_factors.1.map(|health_factor| 1800.0 / health_factor).unwrap_or_default();
+        let equalize = Equalize {
+            eth_price: 2000.0,
+            flash_loan_currency: Currency::ETH,
+            flash_loan_value: 0.0,
+            keep_usdc: 0.0,
+            short_col_delta_usdc: 0.0,
+            short_debt_delta_eth: 0.0,
+            long_col_delta_eth: 0.0,
+            long_debt_delta_usdc: 0.0,
+        };
+        Portfolio {
+            owner: ethabi::Address::zero(),
+            eth_reading: PriceReading { answer: 2000.0, round_id: Uint::one(), updated_at: 1_700_000_000 - age, age },
+            params: StrategyParams::default(),
+            exp_factor: 3.0,
+            snapshot: PortfolioSnapshot {
+                block: 16_000_000,
+                timestamp: 1_700_000_000,
+                eth_price: 2000.0,
+                wallet_eth: 0.0,
+                holdings: Vec::new(),
+                tokens_eth: 0.0,
+                short,
+                short_account: AaveAccount {
+                    total_collateral: zero,
+                    total_debt: zero,
+                    available_borrows: zero,
+                    liquidation_threshold: 0.85,
+                    ltv: 0.8,
+                    health_factor: health_factors.0,
+                },
+                long,
+                long_account: CometAccount {
+                    collateral: Amount::parse("1", 18).unwrap(),
+                    debt: Amount::parse(&format!("{:.6}", long_debt), 6).unwrap(),
+                    collateral_price: 2000.0,
+                    base_price: 1.0,
+                    borrow_collateral_factor: 0.83,
+                    liquidate_collateral_factor: 0.9,
+                    liquidation_factor: 0.95,
+                    is_liquidatable: false,
+                    is_borrow_collateralized: true,
+                },
+                total: short + long,
+                costs: rebalance_costs(&equalize, 0.0009, 0.005, 0.0).unwrap(),
+                equalize,
+                rebalance_down: 1500.0,
+                rebalance_up: 2500.0,
+                liquidation: LiquidationPrices { short_static: None, short_model: None, long_static: None, long_model: None },
+                predictions: Vec::new(),
+            },
+        }
+    }
+
+    fn keys(rule: AlertRule, portfolio: &Portfolio, previous_rebalance: Option<(f64, f64)>) -> Vec<String> {
+        check_rules(&[rule], portfolio, previous_rebalance).into_iter().map(|alert| alert.key).collect()
+    }
+
+    #[test_case(None => Vec::<&str>::new() ; "first refresh")]
+    #[test_case(Some((1900.0, 2100.0)) => Vec::<&str>::new() ; "between the rebalance prices")]
+    #[test_case(Some((2000.0, 2100.0)) => vec!["rebalance_down"] ; "at the down price")]
+    #[test_case(Some((1900.0, 2000.0)) => vec!["rebalance_up"] ; "at the up price")]
+    #[test_case(Some((0.0, 1900.0)) => vec!["rebalance_up"] ; "no down price within the predictions")]
+    #[test_case(Some((0.0, 0.0)) => Vec::<&str>::new() ; "no rebalance within the predictions")]
+    fn rebalance_price_tests(previous_rebalance: Option<(f64, f64)>) -> Vec<String> {
+        keys(AlertRule::RebalancePrice, &portfolio(4.0, 1.0, (Some(1.5), Some(1.5)), 60), previous_rebalance)
+    }
+
+    #[test_case(Some(1.5), Some(1.5) => Vec::<&str>::new() ; "both above")]
+    #[test_case(Some(1.1), Some(1.5) => vec!["short_health_factor"] ; "short below")]
+    #[test_case(Some(1.5), Some(1.1) => vec!["long_health_factor"] ; "long below")]
+    #[test_case(Some(1.0), Some(1.0) => vec!["short_health_factor", "long_health_factor"] ; "both below")]
+    #[test_case(Some(1.2), Some(1.2) => Vec::<&str>::new() ; "at the minimum")]
+    #[test_case(None, None => Vec::<&str>::new() ; "no debt")]
+    fn health_factor_tests(short: Option<f64>, long: Option<f64>) -> Vec<String> {
+        keys(AlertRule::HealthFactor { min: 1.2 }, &portfolio(4.0, 1.0, (short, long), 60), None)
+    }
+
+    #[test_case(4.0, 1.0 => Vec::<&str>::new() ; "within")]
+    #[test_case(6.0, 1.0 => Vec::<&str>::new() ; "at the max")]
+    #[test_case(7.0, 1.0 => vec!["leg_ratio"] ; "above the max")]
+    #[test_case(1.0, 4.0 => vec!["leg_ratio"] ; "below the min")]
+    #[test_case(1.0, 0.0 => vec!["leg_ratio"] ; "empty long leg")]
+    fn leg_ratio_tests(short: f64, long: f64) -> Vec<String> {
+        keys(AlertRule::LegRatio { min: 0.5, max: 6.0 }, &portfolio(short, long, (Some(1.5), Some(1.5)), 60), None)
+    }
+
+    #[test_case(3600 => Vec::<&str>::new() ; "at the max age")]
+    #[test_case(3601 => vec!["stale_oracle"] ; "older")]
+    fn stale_oracle_tests(age: u64) -> Vec<String> {
+        keys(AlertRule::StaleOracle { max_age: 3600 }, &portfolio(4.0, 1.0, (Some(1.5), Some(1.5)), age), None)
+    }
+
+    #[test]
+    fn alert_config_parse() {
+        let config: AlertConfig = toml::from_str(
+            r#"
+rules = [{ rule = "rebalance_price" }, { rule = "health_factor", min = 1.2 }, { rule = "leg_ratio", min = 0.5, max = 6.0 }]
+notifiers = [{ type = "command", program = "notify-send" }, { type = "webhook", url = "http://localhost:9000/hook" }]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.cooldown, DEFAULT_COOLDOWN);
+        assert_eq!(config.rules[1], AlertRule::HealthFactor { min: 1.2 });
+        assert!(AlertEngine::from_config(&config).is_ok());
+        assert!(WebhookNotifier::new("https://hooks.example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_the_alert() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with('}') {
+                let length = stream.read(&mut buffer).unwrap();
+                request.push_str(&String::from_utf8_lossy(&buffer[..length]));
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            request
+        });
+
+        let alert = Alert::new("leg_ratio", String::from("short / long is 7.00"));
+        WebhookNotifier::new(&url).unwrap().notify(&alert).await.unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with(r#"{"key":"leg_ratio","message":"short / long is 7.00"}"#));
+    }
+
+    #[tokio::test]
+    async fn smtp_sends_the_alert() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut data = String::new();
+            writer.write_all(b"220 stand-in\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match &line[..4] {
+                    "EHLO" => b"250-stand-in\r\n250 8BITMIME\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 go on\r\n"
+                    }
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply).unwrap();
+            }
+            data
+        });
+
+        let alert = Alert::new("short_health_factor", String::from("Short (AAVE) health factor 1.10 is below 1.20"));
+        SmtpNotifier::new(&server_address, "bermuda@localhost", &[String::from("me@localhost")])
+            .notify(&alert)
+            .await
+            .unwrap();
+        let data = server.join().unwrap();
+        assert!(data.contains("Subject: bermuda alert: short_health_factor\r\n"));
+        assert!(data.contains("health factor 1.10 is below 1.20"));
+    }
+
+    #[tokio::test]
+    async fn command_notifier_status() {
+        let alert = Alert::new("rebalance_up", String::from("eth price went above"));
+        let check = |script: &str| CommandNotifier::new("sh", &[String::from("-c"), script.to_string()]);
+        assert!(check(r#"test "$BERMUDA_ALERT" = rebalance_up"#).notify(&alert).await.is_ok());
+        assert!(check("exit 1").notify(&alert).await.is_err());
+    }
+}
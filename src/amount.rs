@@ -0,0 +1,176 @@
+use super::error::{BermudaError, Result};
+use ethabi::Uint;
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An exact token amount: the raw integer read on chain and the decimals of its token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Amount {
+    raw: Uint,
+    decimals: u8,
+}
+
+impl Amount {
+    pub fn new(raw: Uint, decimals: u8) -> Self {
+        Self { raw, decimals }
+    }
+
+    /// An amount of ether, from wei.
+    pub fn from_wei(wei: Uint) -> Self {
+        Self::new(wei, 18)
+    }
+
+    pub fn zero(decimals: u8) -> Self {
+        Self::new(Uint::zero(), decimals)
+    }
+
+    /// Parse a decimal string such as `1234.5678`, rejecting more digits than `decimals`.
+    pub fn parse(value: &str, decimals: u8) -> Result<Self> {
+        let invalid = || BermudaError::Input(format!("invalid amount: {}", value));
+        let (integer, fraction) = match value.find('.') {
+            Some(dot) => (&value[..dot], &value[dot + 1..]),
+            None => (value, ""),
+        };
+        if fraction.len() > decimals as usize
+            || !integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit())
+            || integer.len() + fraction.len() == 0
+        {
+            return Err(invalid());
+        }
+        let digits = format!("{}{:0<width$}", integer, fraction, width = decimals as usize);
+        let raw = Uint::from_dec_str(&digits).map_err(|_| invalid())?;
+        Ok(Self::new(raw, decimals))
+    }
+
+    pub fn raw(&self) -> Uint {
+        self.raw
+    }
+
+    pub fn decimals(&self) -> u8 {
+        self.decimals
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.raw.is_zero()
+    }
+
+    /// The same amount with `decimals` digits, truncating the digits that do not fit.
+    pub fn rescale(&self, decimals: u8) -> Result<Self> {
+        let raw = match decimals.cmp(&self.decimals) {
+            Ordering::Equal => self.raw,
+            Ordering::Greater => {
+                let factor = pow10(decimals - self.decimals)?;
+                self.raw
+                    .checked_mul(factor)
+                    .ok_or_else(|| overflow("rescale", self))?
+            }
+            Ordering::Less => self.raw / pow10(self.decimals - decimals)?,
+        };
+        Ok(Self::new(raw, decimals))
+    }
+
+    /// The exact sum, with the decimals of the most precise operand.
+    pub fn checked_add(&self, other: &Amount) -> Result<Self> {
+        let decimals = self.decimals.max(other.decimals);
+        let (a, b) = (self.rescale(decimals)?, other.rescale(decimals)?);
+        let raw = a.raw.checked_add(b.raw).ok_or_else(|| overflow("add", self))?;
+        Ok(Self::new(raw, decimals))
+    }
+
+    /// The exact difference, failing if `other` is larger.
+    pub fn checked_sub(&self, other: &Amount) -> Result<Self> {
+        let decimals = self.decimals.max(other.decimals);
+        let (a, b) = (self.rescale(decimals)?, other.rescale(decimals)?);
+        let raw = a.raw.checked_sub(b.raw).ok_or_else(|| overflow("subtract", self))?;
+        Ok(Self::new(raw, decimals))
+    }
+
+    /// The closest f64, for the price model. This is the only lossy conversion.
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+}
+
+fn pow10(exponent: u8) -> Result<Uint> {
+    match exponent {
+        0..=77 => Ok(Uint::exp10(exponent as usize)),
+        _ => Err(BermudaError::Overflow(format!("10^{} does not fit in 256 bits", exponent))),
+    }
+}
+
+fn overflow(operation: &str, amount: &Amount) -> BermudaError {
+    BermudaError::Overflow(format!("cannot {} {}", operation, amount))
+}
+
+/// The exact decimal value. With a precision, the value is rounded half up to that many digits.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (raw, decimals) = match f.precision() {
+            Some(precision) if precision < self.decimals as usize => {
+                let divisor = Uint::exp10(self.decimals as usize - precision);
+                let (quotient, remainder) = self.raw.div_mod(divisor);
+                let rounded = match remainder >= divisor / 2 {
+                    true => quotient.saturating_add(Uint::one()),
+                    false => quotient,
+                };
+                (rounded, precision)
+            }
+            _ => (self.raw, self.decimals as usize),
+        };
+        let digits = format!("{:0>width$}", raw.to_string(), width = decimals + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - decimals);
+        let fraction = match f.precision() {
+            Some(precision) => format!("{:0<width$}", fraction, width = precision),
+            None => fraction.trim_end_matches('0').to_string(),
+        };
+        match fraction.is_empty() {
+            true => write!(f, "{}", integer),
+            false => write!(f, "{}.{}", integer, fraction),
+        }
+    }
+}
+
+/// Amounts are serialized as exact decimal strings.
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("1000000000000000000", 18, None, "1" ; "one ether")]
+    #[test_case("1234567", 6, None, "1.234567" ; "usdc")]
+    #[test_case("1234567", 6, Some(2), "1.23" ; "rounded down")]
+    #[test_case("1235000", 6, Some(2), "1.24" ; "rounded half up")]
+    #[test_case("5", 18, None, "0.000000000000000005" ; "one wei")]
+    #[test_case("1500000", 6, Some(8), "1.50000000" ; "padded")]
+    fn display_tests(raw: &str, decimals: u8, precision: Option<usize>, expected: &str) {
+        let amount = Amount::new(Uint::from_dec_str(raw).unwrap(), decimals);
+        let formatted = match precision {
+            Some(precision) => format!("{:.*}", precision, amount),
+            None => amount.to_string(),
+        };
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn exact_arithmetic() {
+        // more than u128::MAX wei, which used to panic
+        let large = Amount::from_wei(Uint::from(u128::MAX) * 1000);
+        let wei = Amount::from_wei(Uint::one());
+        let sum = large.checked_add(&wei).unwrap();
+        assert_eq!(sum.checked_sub(&large).unwrap(), wei);
+        assert!(wei.checked_sub(&large).is_err());
+
+        let usdc = Amount::parse("1234.56", 6).unwrap();
+        let dai = Amount::parse("0.000000000000000001", 18).unwrap();
+        assert_eq!(usdc.checked_add(&dai).unwrap().to_string(), "1234.560000000000000001");
+        assert_eq!(usdc.to_f64(), 1234.56);
+        assert!(Amount::parse("1.1234567", 6).is_err());
+    }
+}
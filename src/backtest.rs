@@ -0,0 +1,227 @@
+use super::config::StrategyConfig;
+use super::error::{BermudaError, Result};
+use super::history::PricePoint;
+use super::{initialize_bermuda, predict, Currency, Loan, Prediction};
+use super::{REBALANCE_DOWN_RATIO, REBALANCE_UP_RATIO};
+use ethabi::Uint;
+use serde::Serialize;
+
+/// The portfolio at one point of the price series. Values are in eth unless stated otherwise.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BacktestPoint {
+    pub timestamp: u64,
+    pub price: f64,
+    pub short: f64,
+    pub long: f64,
+    /// The stablecoins taken out by the rebalances, in $.
+    pub kept_usd: f64,
+    pub value_usd: f64,
+    /// The value of the same initial eth, just held.
+    pub hold_value_usd: f64,
+    pub rebalanced: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Backtest {
+    pub points: Vec<BacktestPoint>,
+    pub rebalances: usize,
+    /// What the rebalances cost, in $.
+    pub costs_usd: f64,
+    /// The largest fall from a previous high of the value in $, 0.25 for -25%.
+    pub max_drawdown: f64,
+    pub hold_max_drawdown: f64,
+}
+
+impl Backtest {
+    pub fn final_value_usd(&self) -> f64 {
+        self.points.last().map(|point| point.value_usd).unwrap_or_default()
+    }
+
+    pub fn hold_final_value_usd(&self) -> f64 {
+        self.points.last().map(|point| point.hold_value_usd).unwrap_or_default()
+    }
+
+    /// The points as a CSV, with a header line.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("timestamp,price,short,long,kept_usd,value_usd,hold_value_usd,rebalanced\n");
+        for point in self.points.iter() {
+            let values = [
+                point.timestamp.to_string(),
+                point.price.to_string(),
+                point.short.to_string(),
+                point.long.to_string(),
+                point.kept_usd.to_string(),
+                point.value_usd.to_string(),
+                point.hold_value_usd.to_string(),
+                point.rebalanced.to_string(),
+            ];
+            csv.push_str(&format!("{}\n", values.join(",")));
+        }
+        csv
+    }
+}
+
+/// A leg holding `value` eth at `target_ratio`, as `initialize_bermuda` leaves it.
+fn leg_loan(value: f64, target_ratio: f64) -> Loan {
+    Loan {
+        collateral: value * target_ratio / (target_ratio - 1.0),
+        debt: value / (target_ratio - 1.0),
+    }
+}
+
+/// Replay `prices` through the model, starting with `initial_eth` eth split by the params of `strategy`.
+///
+/// Between two points the legs follow `predict`. When one leg outgrows the other past the
+/// rebalance ratios, both are brought back with `initialize_bermuda`, paying `cost_ratio` of
+/// the flash loan (premium, swap fees and slippage) and taking out `keep_ratio` as stablecoins.
+pub fn backtest(prices: &[PricePoint], initial_eth: f64, strategy: &StrategyConfig, cost_ratio: f64) -> Result<Backtest> {
+    let first = prices
+        .first()
+        .ok_or_else(|| BermudaError::Input("the price series is empty".to_string()))?;
+    let params = strategy.params();
+    params.validate(1.0)?;
+    let invested = initial_eth * (1.0 - params.keep_ratio);
+    let mut current = Prediction {
+        price: first.price,
+        short: invested * params.short_share,
+        long: invested * params.long_share,
+    };
+    let exp_factor = strategy.exponent()?;
+    let mut kept_usd = initial_eth * params.keep_ratio * first.price;
+    let (mut rebalances, mut costs_usd) = (0, 0.0);
+    let mut points = Vec::with_capacity(prices.len());
+
+    for point in prices {
+        let mut next = predict(&current, point.price, exp_factor)?;
+        let rebalanced = next.short * REBALANCE_UP_RATIO <= next.long
+            || next.long * REBALANCE_DOWN_RATIO <= next.short;
+        if rebalanced {
+            let equalize = initialize_bermuda(
+                leg_loan(next.short, params.target_ratio),
+                leg_loan(next.long, params.target_ratio),
+                point.price,
+                &params,
+            )?;
+            let cost_usd = cost_ratio
+                * match equalize.flash_loan_currency {
+                    Currency::ETH => equalize.flash_loan_value * point.price,
+                    Currency::USDC => equalize.flash_loan_value,
+                };
+            let invested = (next.short + next.long) * (1.0 - params.keep_ratio) - cost_usd / point.price;
+            kept_usd += equalize.keep_usdc;
+            costs_usd += cost_usd;
+            rebalances += 1;
+            next = Prediction {
+                price: point.price,
+                short: invested * params.short_share,
+                long: invested * params.long_share,
+            };
+        }
+        points.push(BacktestPoint {
+            timestamp: point.timestamp,
+            price: point.price,
+            short: next.short,
+            long: next.long,
+            kept_usd,
+            value_usd: (next.short + next.long) * point.price + kept_usd,
+            hold_value_usd: initial_eth * point.price,
+            rebalanced,
+        });
+        current = next;
+    }
+
+    Ok(Backtest {
+        max_drawdown: max_drawdown(points.iter().map(|point| point.value_usd)),
+        hold_max_drawdown: max_drawdown(points.iter().map(|point| point.hold_value_usd)),
+        points,
+        rebalances,
+        costs_usd,
+    })
+}
+
+fn max_drawdown<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let mut high = f64::MIN;
+    let mut drawdown: f64 = 0.0;
+    for value in values {
+        high = high.max(value);
+        if high > 0.0 {
+            drawdown = drawdown.max(1.0 - value / high);
+        }
+    }
+    drawdown
+}
+
+/// Parse a `timestamp,price` CSV, with or without a header line.
+pub fn parse_price_csv(content: &str) -> Result<Vec<PricePoint>> {
+    let mut points = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || BermudaError::Input(format!("line {}: expected timestamp,price, got {}", index + 1, line));
+        let mut fields = line.split(',').map(str::trim);
+        let (timestamp, price) = match (fields.next(), fields.next()) {
+            (Some(timestamp), Some(price)) => (timestamp, price),
+            _ => return Err(invalid()),
+        };
+        match (timestamp.parse(), price.parse()) {
+            (Ok(timestamp), Ok(price)) => points.push(PricePoint {
+                round_id: Uint::zero(),
+                timestamp,
+                price,
+            }),
+            _ if index == 0 => continue,
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(prices: &[f64]) -> Vec<PricePoint> {
+        prices
+            .iter()
+            .enumerate()
+            .map(|(index, price)| PricePoint {
+                round_id: Uint::zero(),
+                timestamp: index as u64,
+                price: *price,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flat_prices_never_rebalance() {
+        let result = backtest(&series(&[2000.0, 2000.0, 2000.0]), 10.0, &StrategyConfig::default(), 0.003).unwrap();
+        assert_eq!(result.rebalances, 0);
+        assert_eq!(result.max_drawdown, 0.0);
+        assert_eq!(result.final_value_usd().round(), 20000.0);
+    }
+
+    #[test]
+    fn rally_rebalances_and_costs() {
+        let prices = series(&[1000.0, 1500.0, 2000.0, 3000.0, 4000.0, 2000.0, 1000.0]);
+        let result = backtest(&prices, 10.0, &StrategyConfig::default(), 0.003).unwrap();
+        assert!(result.rebalances > 0);
+        assert!(result.costs_usd > 0.0);
+        assert_eq!(result.hold_max_drawdown, 0.75);
+        assert!(result.points.iter().any(|point| point.rebalanced));
+
+        let csv = result.to_csv();
+        assert_eq!(csv.lines().count(), prices.len() + 1);
+        assert!(csv.lines().nth(1).unwrap().starts_with("0,1000,"));
+    }
+
+    #[test]
+    fn csv_tests() {
+        let points = parse_price_csv("timestamp,price\n1600000000,350.5\n1600003600, 351\n").unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].price, 351.0);
+        assert!(parse_price_csv("1600000000,350.5\nnope").is_err());
+        assert!(matches!(backtest(&[], 1.0, &StrategyConfig::default(), 0.0), Err(BermudaError::Input(_))));
+    }
+}
@@ -4,130 +4,262 @@ extern crate tera;
 
 use async_jsonrpc_client::HttpTransport;
 use ethabi::Address;
-use bermuda::{Aave, Compound, humanize, Prediction, predict, initialize_bermuda};
+use bermuda::{Aave, Compound, humanize};
 use bermuda::{Chainlink, SmartWallet};
-use bermuda::ERC20;
-use bermuda::HttpBlockchainReader;
+use bermuda::{BlockTag, BlockchainReader, HttpBlockchainReader};
+use bermuda::{Config, StrategyConfig, DEFAULT_NODE};
+use bermuda::{backtest, format_timestamp, parse_end_timestamp, parse_price_csv, parse_timestamp, RoundCache};
+use bermuda::{build_rebalance, Loan, Simulator, TransactionOptions};
+use bermuda::{Portfolio, PortfolioConfig, PortfolioSnapshot};
+use bermuda::{render_watch, BermudaError, WatchValues};
+use bermuda::AlertEngine;
+use bermuda::{Profile, SnapshotStore, StoredSnapshot};
+use cli_table::{format::Justify, print_stdout, Cell, Style, Table};
 use std::error::Error;
 use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tera::Context;
 use tera::Tera;
 
-const DAI_ADDRESS: &str = "6b175474e89094c44da98b954eedeac495271d0f";
-const USDC_ADDRESS: &str = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
-
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    
+async fn main() {
+    if let Err(error) = run().await {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+
     let app_m = clap_app!(
         vault =>
         (version: "0.3.0")
         (author: "François Bastien <fmrbastien@gmail.com>")
         (about: "Get informations about your makerDAO vault.")
         (@subcommand show =>
-         (@arg NODE: -n --node +takes_value default_value("http://localhost:8545") "Ethereum node to call" )
-         (@arg SMART_WALLET: -s --sw +takes_value +required "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to call, http://localhost:8545 by default" )
+         (@arg SMART_WALLET: -s --sw +takes_value "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg BLOCK: -b --block +takes_value default_value("latest") "Block to read the portfolio at: latest, safe, finalized, a number or a hash" )
+         (@arg TOKEN: -t --token +takes_value +multiple number_of_values(1) "ERC20 token of the owner wallet: <token>:stable, <token>:eth, <token>:usd=<chainlink feed> or <token>:eth=<chainlink feed>. Defaults to DAI and USDC." )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+         (@arg SLIPPAGE: --slippage +takes_value "Slippage tolerance of the rebalance swap, 0.005 by default" )
+         (@arg FORMAT: --format +takes_value possible_value[text json csv table] default_value("text") "Output format: text, json, csv or table" )
         )
         (@subcommand html =>
-          (@arg NODE: -n --node +takes_value default_value("http://localhost:8545") "Ethereum node to call" )
-         (@arg SMART_WALLET: -s --sw +takes_value +required "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+          (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to call, http://localhost:8545 by default" )
+         (@arg SMART_WALLET: -s --sw +takes_value "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg BLOCK: -b --block +takes_value default_value("latest") "Block to read the portfolio at: latest, safe, finalized, a number or a hash" )
+         (@arg TOKEN: -t --token +takes_value +multiple number_of_values(1) "ERC20 token of the owner wallet: <token>:stable, <token>:eth, <token>:usd=<chainlink feed> or <token>:eth=<chainlink feed>. Defaults to DAI and USDC." )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+         (@arg SLIPPAGE: --slippage +takes_value "Slippage tolerance of the rebalance swap, 0.005 by default" )
           (@arg FILE: -f --file +takes_value default_value("index.html") "file name where to output the generated html" )
-          (@arg EURUSD: -r --rate +takes_value default_value("1.06") "The price of 1€ in $" )
+          (@arg EURUSD: -r --rate +takes_value "The price of 1€ in $, read from the EUR/USD chainlink feed by default" )
+        )
+        (@subcommand rebalance =>
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to call, http://localhost:8545 by default" )
+         (@arg SMART_WALLET: -s --sw +takes_value "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg BLOCK: -b --block +takes_value default_value("latest") "Block to read the portfolio at: latest, safe, finalized, a number or a hash" )
+         (@arg TOKEN: -t --token +takes_value +multiple number_of_values(1) "ERC20 token of the owner wallet: <token>:stable, <token>:eth, <token>:usd=<chainlink feed> or <token>:eth=<chainlink feed>. Defaults to DAI and USDC." )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+         (@arg SLIPPAGE: --slippage +takes_value "Slippage tolerance of the rebalance swap, 0.005 by default" )
+         (@arg RECEIVER: --receiver +takes_value "Aave flash loan receiver calling the smart wallet back, flash_receiver of the profile by default" )
+         (@arg OUTPUT: -o --output +takes_value "JSON file where to write the transaction, printed by default" )
+        )
+        (@subcommand simulate =>
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to call, http://localhost:8545 by default" )
+         (@arg SMART_WALLET: -s --sw +takes_value "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg BLOCK: -b --block +takes_value default_value("latest") "Block to read the portfolio at: latest, safe, finalized, a number or a hash" )
+         (@arg TOKEN: -t --token +takes_value +multiple number_of_values(1) "ERC20 token of the owner wallet: <token>:stable, <token>:eth, <token>:usd=<chainlink feed> or <token>:eth=<chainlink feed>. Defaults to DAI and USDC." )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+         (@arg SLIPPAGE: --slippage +takes_value "Slippage tolerance of the rebalance swap, 0.005 by default" )
+         (@arg RECEIVER: --receiver +takes_value "Aave flash loan receiver calling the smart wallet back, flash_receiver of the profile by default" )
+         (@arg FORK: --fork +takes_value +required "Local anvil or hardhat fork of the node to send the transaction on" )
+        )
+        (@subcommand watch =>
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to call, http://localhost:8545 by default" )
+         (@arg SMART_WALLET: -s --sw +takes_value "The address of the smart wallet. This is not your ethereum address, but your smart wallet address in DefiSaver." )
+         (@arg TOKEN: -t --token +takes_value +multiple number_of_values(1) "ERC20 token of the owner wallet: <token>:stable, <token>:eth, <token>:usd=<chainlink feed> or <token>:eth=<chainlink feed>. Defaults to DAI and USDC." )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+         (@arg SLIPPAGE: --slippage +takes_value "Slippage tolerance of the rebalance swap, 0.005 by default" )
+         (@arg INTERVAL: -i --interval +takes_value "Seconds between two refreshes, or between two polls of the new blocks with --blocks. 60 by default, 4 with --blocks" )
+         (@arg BLOCKS: --blocks "Refresh on every new block, with an eth_newBlockFilter" )
+        )
+        (@subcommand backtest =>
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg NODE: -n --node +takes_value "Ethereum node to read the chainlink history from, http://localhost:8545 by default" )
+         (@arg CSV: --csv +takes_value "timestamp,price CSV to replay instead of the chainlink history" )
+         (@arg FROM: --from +takes_value "Start of the chainlink history: YYYY-MM-DD or a unix timestamp" )
+         (@arg TO: --to +takes_value "End of the chainlink history, included: YYYY-MM-DD (to its last second) or a unix timestamp, now by default" )
+         (@arg VALUE: --value +takes_value default_value("1") "Initial portfolio, in eth" )
+         (@arg COST: --cost +takes_value default_value("0.003") "Cost of a rebalance, as a fraction of the flash loan" )
+         (@arg OUTPUT: -o --output +takes_value "CSV file where to write the value path" )
+         (@arg TARGET_RATIO: --("target-ratio") +takes_value "Collateral / debt ratio the loans are rebalanced to, 1.5 by default" )
+         (@arg KEEP_RATIO: --("keep-ratio") +takes_value "Part of the portfolio taken out as USDC at each rebalance, 0.1 by default" )
+         (@arg SHORT_SHARE: --("short-share") +takes_value "Part of the invested value put in the short leg, 2/3 by default" )
+         (@arg LONG_SHARE: --("long-share") +takes_value "Part of the invested value put in the long leg, 1 - short share by default" )
+        )
+        (@subcommand history =>
+         (@arg CONFIG: -c --config +takes_value "TOML config file, ~/.config/bermuda/config.toml by default" )
+         (@arg PROFILE: -p --profile +takes_value "Profile of the config file to use" )
+         (@arg SMART_WALLET: -s --sw +takes_value "Smart wallet whose snapshots are listed, the one of the profile by default" )
+         (@arg ALL: --all "List the snapshots of every smart wallet" )
+         (@arg DB: --db +takes_value "SQLite file of the snapshots, history of the profile or ~/.local/share/bermuda/history.sqlite by default" )
+         (@arg FROM: --from +takes_value "Start of the listed snapshots: YYYY-MM-DD or a unix timestamp" )
+         (@arg TO: --to +takes_value "End of the listed snapshots, included: YYYY-MM-DD (to its last second) or a unix timestamp, now by default" )
+         (@arg FORMAT: --format +takes_value possible_value[text json csv] default_value("text") "Output format: text, json or csv" )
+         (@arg OUTPUT: -o --output +takes_value "File where to write the snapshots, printed by default" )
         ))
         .get_matches();
 
     match app_m.subcommand() {
         (sub_c, Some(sub_m)) => {
-            let node = sub_m.value_of("NODE").unwrap();
-            let transport = HttpTransport::new(node);
-            let reader: HttpBlockchainReader = HttpBlockchainReader::new(transport)?;
-            let aave = Aave::new(&reader)?;
-            let compound = Compound::new(&reader).await?;
-            let chainlink = Chainlink::new(&reader)?;
-            let dai = ERC20::new(&reader, DAI_ADDRESS.parse()?)?;
-            let usdc = ERC20::new(&reader, USDC_ADDRESS.parse()?)?;
-            let price = chainlink.get_eth_price().await?;
-
-            let smart_wallet = sub_m.value_of("SMART_WALLET").unwrap();
-            let smart_wallet = smart_wallet.strip_prefix("0x").unwrap_or(smart_wallet);
-            let smart_wallet_contract = SmartWallet::new(&reader, smart_wallet)?;
-            let wallet: Address = smart_wallet_contract.get_owner().await?;
-            let short = aave.get_eth_value(smart_wallet).await?;
-            let long = compound.get_eth_value(smart_wallet, price).await?;
-            
-            let sl = aave.get_loan(smart_wallet).await?;
-            let ll = compound.get_loan(smart_wallet, price).await?;
-            let equalize = initialize_bermuda(sl, ll, price)?;
-
-            let eth_value = reader.get_eth_balance(&wallet).await?;
-            let dai_eth_value = dai.get_value(&wallet).await? / price;
-            let dai_eth_value = dai_eth_value + (usdc.get_value(&wallet).await? / price);
-            let total = eth_value + short + long + dai_eth_value;
-
-            let current = Prediction{                          
-                price,                                                                              
-                short,                                
-                long                                  
+            // command line flags override the profile of the config file
+            let config = match sub_m.value_of("CONFIG") {
+                Some(path) => Config::load(Path::new(path))?,
+                None => match Config::default_path() {
+                    Some(path) if path.exists() => Config::load(&path)?,
+                    _ => Config::default(),
+                },
             };
-            let mut predictions = Vec::new();
-            for price in (500..1000).step_by(50).map(|x| x as f64){
-                predictions.push(predict(&current, price)?);
+            let profile = config.profile(sub_m.value_of("PROFILE"))?;
+            let contracts = config.contracts(&profile)?;
+            let strategy = strategy_from_args(sub_m, profile.strategy)?;
+            let node = sub_m.value_of("NODE").or(profile.node.as_deref()).unwrap_or(DEFAULT_NODE);
+            if sub_c == "backtest" {
+                return run_backtest(sub_m, node, contracts.chainlink, &strategy).await;
             }
-            for price in (1000..3000).step_by(100).map(|x| x as f64){
-                predictions.push(predict(&current, price)?);
+            if sub_c == "history" {
+                return run_history(sub_m, &profile);
             }
-            for price in (3000..5000).step_by(250).map(|x| x as f64){
-                predictions.push(predict(&current, price)?);
+            let mut portfolio_config = PortfolioConfig::from_profile(&config, &profile)?;
+            portfolio_config.strategy = strategy;
+            if let Some(tokens) = sub_m.values_of("TOKEN") {
+                portfolio_config.tokens = tokens.map(|token| token.parse()).collect::<Result<_, _>>()?;
             }
-            for price in (5000..10000).step_by(500).map(|x| x as f64){
-                predictions.push(predict(&current, price)?);
+            if let Some(slippage) = sub_m.value_of("SLIPPAGE") {
+                portfolio_config.costs.slippage = slippage.parse()?;
             }
-            for price in (10000..=20000).step_by(1000).map(|x| x as f64){
-                predictions.push(predict(&current, price)?);
+            let smart_wallet = sub_m
+                .value_of("SMART_WALLET")
+                .or(profile.smart_wallet.as_deref())
+                .ok_or("no smart wallet, give one with --sw or in the config file")?;
+            let smart_wallet = smart_wallet.strip_prefix("0x").unwrap_or(smart_wallet);
+            if sub_c == "watch" {
+                return run_watch(sub_m, node, smart_wallet, &portfolio_config, &profile).await;
             }
-            
-            let rebalance_up = match predictions.iter().find(|&p| (p.short*6.0 <= p.long)) {
-                Some(prediction) => prediction.price,
-                _ => 0.0,
-            };
-            let rebalance_down = match predictions.iter().rev().find(|&p| p.long*36.0 <= p.short) {
-                Some(prediction) => prediction.price,
-                _ => 0.0,
-            };
-            
+            let transport = HttpTransport::new(node);
+            let reader: HttpBlockchainReader = HttpBlockchainReader::new(transport)?;
+            // pin every read of the snapshot to the same block
+            let block: BlockTag = sub_m.value_of("BLOCK").unwrap().parse()?;
+            let block_number = reader.resolve_block(block).await?;
+            let reader = reader.at_block(BlockTag::Number(block_number));
+            let portfolio = Portfolio::load(&reader, smart_wallet, &portfolio_config).await?;
+            if let Some(store) = open_store(&profile) {
+                record(&store, &portfolio, smart_wallet);
+            }
+            let snapshot = &portfolio.snapshot;
+            let price = snapshot.eth_price;
+
             match sub_c {
+                "show" if sub_m.value_of("FORMAT") != Some("text") => {
+                    match sub_m.value_of("FORMAT") {
+                        Some("json") => println!("{}", serde_json::to_string_pretty(snapshot)?),
+                        Some("csv") => print!("{}", snapshot.to_csv()),
+                        _ => print_table(snapshot)?,
+                    }
+                }
                 "show" => {
-                    println!("eth price: {:.2} $", price);
-                    println!("");
-
-                    println!("eth wallet: {:.2} eth ({:.2} $)", eth_value, eth_value * price);
-                    println!("dai wallet: {:.2} eth ({:.2} $)", dai_eth_value, dai_eth_value * price);
-                    println!("");
-
-                    println!("Short: {:.2} eth ({:.2} $)", short, short * price);
-                    println!("Long: {:.2} eth ({:.2} $)", long, long * price);
-                    println!("Long + short: {:.2} eth ({:.2} $)", long+short, (long+short) * price);
-                    println!("");
-                    match equalize.flash_loan_currency {
+                    println!("block: {}", block_number);
+                    println!("eth price: {:.2} $ (round {}, updated {}s ago)", price, portfolio.eth_reading.round_id, portfolio.eth_reading.age);
+                    println!();
+
+                    println!("eth wallet: {:.2} eth ({:.2} $)", snapshot.wallet_eth, snapshot.wallet_eth * price);
+                    for holding in snapshot.holdings.iter() {
+                        println!("{} wallet: {:.2} {} ({:.2} eth, {:.2} $)", holding.token.symbol, holding.balance, holding.token.symbol, holding.eth_value, holding.usd_value);
+                    }
+                    println!();
+
+                    println!("Short: {:.2} eth ({:.2} $)", snapshot.short, snapshot.short * price);
+                    match (snapshot.short_account.health_factor, snapshot.short_account.liquidation_price(price), snapshot.short_account.distance_to_liquidation()) {
+                        (Some(health_factor), Some(liquidation_price), Some(distance)) => {
+                            println!("Short (AAVE) health factor: {:.2}, liquidation at {:.2} $ ({:+.1}%)", health_factor, liquidation_price, distance * 100.0);
+                        }
+                        _ => println!("Short (AAVE) health factor: no debt"),
+                    }
+                    println!("Long: {:.2} eth ({:.2} $)", snapshot.long, snapshot.long * price);
+                    match (snapshot.long_account.health_factor(), snapshot.long_account.liquidation_price(), snapshot.long_account.distance_to_liquidation()) {
+                        (Some(health_factor), Some(liquidation_price), Some(distance)) => {
+                            println!("Long (Compound) health factor: {:.2}, liquidation at {:.2} $ ({:+.1}%)", health_factor, liquidation_price, distance * 100.0);
+                        }
+                        _ => println!("Long (Compound) health factor: no debt"),
+                    }
+                    println!("Long (Compound) borrow capacity left: {:.2} $", snapshot.long_account.borrow_capacity_left());
+                    if snapshot.long_account.is_liquidatable {
+                        println!("Long (Compound) is liquidatable!");
+                    }
+                    println!("Long + short: {:.2} eth ({:.2} $)", snapshot.long+snapshot.short, (snapshot.long+snapshot.short) * price);
+                    println!();
+                    match snapshot.equalize.flash_loan_currency {
                         bermuda::Currency::ETH => {
-                            println!("Flash borrow {:.2} eth", equalize.flash_loan_value);
-                            println!("Short (AAVE): Repay {:.2} eth of debt and withdraw {:.2} $ of collateral", equalize.short_debt_delta_eth, equalize.short_col_delta_usdc);
-                            println!("Long (Compound): Add {:.2} eth of collateral and borrow {:.2} $", equalize.long_col_delta_eth, equalize.long_debt_delta_usdc);
-                            println!("Sell ~ {:.2} $ for {:.2} eth", equalize.flash_loan_value, equalize.flash_loan_value * price);
-                            println!("Flash repay {:.2} eth", equalize.flash_loan_value);
+                            println!("Flash borrow {:.2} eth", snapshot.equalize.flash_loan_value);
+                            println!("Short (AAVE): Repay {:.2} eth of debt and withdraw {:.2} $ of collateral", snapshot.equalize.short_debt_delta_eth, snapshot.equalize.short_col_delta_usdc);
+                            println!("Long (Compound): Add {:.2} eth of collateral and borrow {:.2} $", snapshot.equalize.long_col_delta_eth, snapshot.equalize.long_debt_delta_usdc);
+                            println!("Sell ~ {:.2} $ for {:.2} eth", snapshot.equalize.flash_loan_value, snapshot.equalize.flash_loan_value * price);
+                            println!("Flash repay {:.2} eth", snapshot.equalize.flash_loan_value);
                         },
                         bermuda::Currency::USDC => {
-                            println!("Flash borrow {:.2} $", equalize.flash_loan_value);
-                            println!("Long (Compound): Repay {:.2} $ of debt and withdraw {:.2} eth of collateral", equalize.long_debt_delta_usdc, equalize.long_col_delta_eth);
-                            println!("Short (AAVE): Add {:.2} $ of collateral and borrow {:.2} eth", equalize.short_col_delta_usdc, equalize.short_debt_delta_eth);
-                            println!("Sell {:.2} eth for ~ {:.2} $", equalize.long_col_delta_eth + equalize.short_debt_delta_eth, (equalize.long_col_delta_eth + equalize.short_debt_delta_eth) * price);
-                            println!("Flash repay {:.2} $", equalize.flash_loan_value);
+                            println!("Flash borrow {:.2} $", snapshot.equalize.flash_loan_value);
+                            println!("Long (Compound): Repay {:.2} $ of debt and withdraw {:.2} eth of collateral", snapshot.equalize.long_debt_delta_usdc, snapshot.equalize.long_col_delta_eth);
+                            println!("Short (AAVE): Add {:.2} $ of collateral and borrow {:.2} eth", snapshot.equalize.short_col_delta_usdc, snapshot.equalize.short_debt_delta_eth);
+                            println!("Sell {:.2} eth for ~ {:.2} $", snapshot.equalize.long_col_delta_eth + snapshot.equalize.short_debt_delta_eth, (snapshot.equalize.long_col_delta_eth + snapshot.equalize.short_debt_delta_eth) * price);
+                            println!("Flash repay {:.2} $", snapshot.equalize.flash_loan_value);
                         }
                     }
-                    println!("Keep ~ {:.2} $", equalize.keep_usdc);
-                    println!("");
+                    println!("Keep ~ {:.2} $", snapshot.equalize.keep_usdc);
+                    println!();
+
+                    println!("Rebalance down at {:.2} $, up at {:.2} $", snapshot.rebalance_down, snapshot.rebalance_up);
+                    // a model price is only missing with a static one when the leg has no equity left
+                    let format_price = |price: Option<f64>, static_price: Option<f64>| match (price, static_price) {
+                        (Some(price), _) => format!("{:.2} $", price),
+                        (None, Some(_)) => String::from("none, no equity left"),
+                        (None, None) => String::from("never"),
+                    };
+                    let liquidation = &snapshot.liquidation;
+                    println!("Short liquidation at {} (model: {})", format_price(liquidation.short_static, None), format_price(liquidation.short_model, liquidation.short_static));
+                    println!("Long liquidation at {} (model: {})", format_price(liquidation.long_static, None), format_price(liquidation.long_model, liquidation.long_static));
+                    println!();
 
-                    println!("Total: {:.2} eth ({:.2} $)", total, total * price);
+                    println!("Total: {:.2} eth ({:.2} $)", snapshot.total, snapshot.total * price);
+                    println!("Total after rebalance costs: {:.2} eth ({:.2} $)", snapshot.net_total(), snapshot.net_total() * price);
                 }
                 "html" => {
                     let mut tera = match Tera::new("*.html") {
@@ -139,22 +271,46 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     };
                     tera.register_filter("humanize", humanize);
                     tera.add_raw_template("index.html", TEMPLATE)?;
-                    let eur_usd_str = sub_m.value_of("EURUSD").unwrap();
-                    let eur_usd = eur_usd_str.parse::<f64>().unwrap();
+                    let eur_usd = match sub_m.value_of("EURUSD") {
+                        Some(eur_usd) => eur_usd.parse::<f64>()?,
+                        None => match profile.eur_usd {
+                            Some(eur_usd) => eur_usd,
+                            None => {
+                                config
+                                    .feed_registry(&profile)?
+                                    .with_limits(portfolio_config.feed_oracle)
+                                    .get_rate(&reader, "EUR/USD")
+                                    .await?
+                            }
+                        },
+                    };
                     let usd_eur = 1.0 / eur_usd;
                     let mut context = Context::new();
+                    context.insert("block", &block_number);
                     context.insert("eth_price", &price);
-                    context.insert("eth_value", &(eth_value));
-                    context.insert("dai_eth_value", &(dai_eth_value));
-                    context.insert("eth_short", &short);
-                    context.insert("eth_long", &long);
+                    context.insert("eth_reading", &portfolio.eth_reading);
+                    context.insert("eth_value", &snapshot.wallet_eth);
+                    context.insert("tokens_eth_value", &snapshot.tokens_eth);
+                    context.insert("holdings", &snapshot.holdings);
+                    context.insert("eth_short", &snapshot.short);
+                    context.insert("aave_account", &snapshot.short_account);
+                    context.insert("short_liquidation_price", &snapshot.short_account.liquidation_price(price));
+                    context.insert("eth_long", &snapshot.long);
+                    context.insert("compound_account", &snapshot.long_account);
+                    context.insert("long_health_factor", &snapshot.long_account.health_factor());
+                    context.insert("long_liquidation_price", &snapshot.long_account.liquidation_price());
+                    context.insert("long_borrow_capacity_left", &snapshot.long_account.borrow_capacity_left());
                     context.insert("usd_eur", &usd_eur);
-                    context.insert("total", &total);
-                    context.insert("rebalance_down", &rebalance_down);
-                    context.insert("current", &current);
-                    context.insert("rebalance_up", &rebalance_up);
-                    context.insert("predictions", &predictions);
-                    context.insert("equalize", &equalize);
+                    context.insert("total", &snapshot.total);
+                    context.insert("rebalance_down", &snapshot.rebalance_down);
+                    context.insert("current", &portfolio.current());
+                    context.insert("rebalance_up", &snapshot.rebalance_up);
+                    context.insert("liquidation", &snapshot.liquidation);
+                    context.insert("predictions", &snapshot.predictions);
+                    context.insert("exp_factor", &portfolio.exp_factor);
+                    context.insert("equalize", &snapshot.equalize);
+                    context.insert("costs", &snapshot.costs);
+                    context.insert("net_total", &snapshot.net_total());
 
                     let html = tera.render("index.html", &context)?;
                     let file_name = sub_m.value_of("FILE").unwrap();
@@ -162,6 +318,60 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
                     fs::write(file_name, html).expect("Unable to write file");
                 }
+                "rebalance" | "simulate" => {
+                    let receiver = sub_m
+                        .value_of("RECEIVER")
+                        .or(profile.flash_receiver.as_deref())
+                        .ok_or("no flash loan receiver, give one with --receiver or in the config file")?;
+                    let rebalance_contracts = contracts.rebalance(receiver.strip_prefix("0x").unwrap_or(receiver).parse()?)?;
+                    // leave 30 minutes to sign and send the transaction
+                    let deadline = reader.block_timestamp().await? + 1800;
+                    let options = TransactionOptions {
+                        from: portfolio.owner,
+                        gas: profile.costs.gas_limit,
+                        chain_id: reader.eth_chain_id().await?,
+                    };
+                    let rebalance = build_rebalance(&snapshot.equalize, &snapshot.costs, &rebalance_contracts, &SmartWallet::new(&reader, smart_wallet)?, deadline, &options)?;
+                    if sub_c == "rebalance" {
+                        let json = serde_json::to_string_pretty(&rebalance)?;
+                        match sub_m.value_of("OUTPUT") {
+                            Some(path) => fs::write(path, json)?,
+                            None => println!("{}", json),
+                        }
+                        return Ok(());
+                    }
+
+                    let transaction = &rebalance.transaction;
+                    for step in rebalance.steps.iter() {
+                        println!("{}", step.description);
+                    }
+                    println!();
+                    let fork_reader = HttpBlockchainReader::new(HttpTransport::new(sub_m.value_of("FORK").unwrap()))?;
+                    let fork_aave = Aave::at(&fork_reader, contracts.aave)?;
+                    let fork_compound = Compound::at(&fork_reader, contracts.compound, contracts.weth)?;
+                    let simulator = Simulator::new(&fork_reader);
+                    simulator
+                        .call(transaction)
+                        .await
+                        .map_err(|error| format!("the rebalance fails on the fork: {}", error))?;
+                    let short_before = fork_aave.get_loan(smart_wallet).await?;
+                    let long_before = fork_compound.get_loan(smart_wallet, price).await?;
+                    let receipt = simulator
+                        .send(transaction)
+                        .await
+                        .map_err(|error| format!("the rebalance fails on the fork: {}", error))?;
+                    let short_after = fork_aave.get_loan(smart_wallet).await?;
+                    let long_after = fork_compound.get_loan(smart_wallet, price).await?;
+
+                    println!("Mined in block {} of the fork, {} gas used ({:#x})", receipt.block_number, receipt.gas_used, receipt.transaction_hash);
+                    let format_loan = |loan: &Loan| format!(
+                        "{:.4} eth of collateral, {:.4} eth of debt, ratio {:.3}",
+                        loan.collateral, loan.debt, loan.collateral / loan.debt
+                    );
+                    println!("Short (AAVE): {} -> {}", format_loan(&short_before), format_loan(&short_after));
+                    println!("Long (Compound): {} -> {}", format_loan(&long_before), format_loan(&long_after));
+                    println!("Target ratio: {:.3}", portfolio.params.target_ratio);
+                }
                 _ => println!("{}", app_m.usage()),
             }
         }
@@ -172,7 +382,268 @@ async fn main() -> Result<(), Box<dyn Error>> {
 }
 
 
+/// The positions, prices, rebalance and predictions of the snapshot as tables.
+fn print_table(snapshot: &PortfolioSnapshot) -> Result<(), Box<dyn Error>> {
+    let price = snapshot.eth_price;
+    let row = |name: String, eth: f64| vec![name.cell(), format!("{:.4}", eth).cell().justify(Justify::Right), format!("{:.2}", eth * price).cell().justify(Justify::Right)];
+    let mut positions = vec![row(String::from("Wallet ETH"), snapshot.wallet_eth)];
+    for holding in snapshot.holdings.iter() {
+        positions.push(row(format!("Wallet {}", holding.token.symbol), holding.eth_value));
+    }
+    positions.push(row(String::from("Short (AAVE)"), snapshot.short));
+    positions.push(row(String::from("Long (Compound)"), snapshot.long));
+    positions.push(row(String::from("Total"), snapshot.total));
+    positions.push(row(String::from("Total after rebalance costs"), snapshot.net_total()));
+    print_stdout(positions.table().title(vec!["Position".cell().bold(true), "eth".cell().bold(true), "$".cell().bold(true)]))?;
+
+    let format_price = |price: Option<f64>, static_price: Option<f64>| match (price, static_price) {
+        (Some(price), _) => format!("{:.2}", price),
+        (None, Some(_)) => String::from("no equity left"),
+        (None, None) => String::from("never"),
+    };
+    let liquidation = &snapshot.liquidation;
+    let prices = vec![
+        vec!["ETH".cell(), format!("{:.2}", price).cell().justify(Justify::Right)],
+        vec!["Rebalance down".cell(), format!("{:.2}", snapshot.rebalance_down).cell().justify(Justify::Right)],
+        vec!["Rebalance up".cell(), format!("{:.2}", snapshot.rebalance_up).cell().justify(Justify::Right)],
+        vec!["Short liquidation".cell(), format_price(liquidation.short_static, None).cell().justify(Justify::Right)],
+        vec!["Short liquidation (model)".cell(), format_price(liquidation.short_model, liquidation.short_static).cell().justify(Justify::Right)],
+        vec!["Long liquidation".cell(), format_price(liquidation.long_static, None).cell().justify(Justify::Right)],
+        vec!["Long liquidation (model)".cell(), format_price(liquidation.long_model, liquidation.long_static).cell().justify(Justify::Right)],
+    ];
+    print_stdout(prices.table().title(vec!["Price".cell().bold(true), "$".cell().bold(true)]))?;
+
+    let equalize = &snapshot.equalize;
+    let amount = |value: f64, decimals: usize| format!("{:.*}", decimals, value).cell().justify(Justify::Right);
+    let flash_loan_decimals = match equalize.flash_loan_currency {
+        bermuda::Currency::ETH => 4,
+        bermuda::Currency::USDC => 2,
+    };
+    let rebalance = vec![
+        vec![format!("Flash loan ({:?})", equalize.flash_loan_currency).cell(), amount(equalize.flash_loan_value, flash_loan_decimals)],
+        vec!["Short collateral ($)".cell(), amount(equalize.short_col_delta_usdc, 2)],
+        vec!["Short debt (eth)".cell(), amount(equalize.short_debt_delta_eth, 4)],
+        vec!["Long collateral (eth)".cell(), amount(equalize.long_col_delta_eth, 4)],
+        vec!["Long debt ($)".cell(), amount(equalize.long_debt_delta_usdc, 2)],
+        vec!["Keep ($)".cell(), amount(equalize.keep_usdc, 2)],
+        vec!["Cost (eth)".cell(), amount(snapshot.costs.total_cost_eth, 4)],
+    ];
+    print_stdout(rebalance.table().title(vec!["Rebalance".cell().bold(true), "Change".cell().bold(true)]))?;
+
+    let predictions: Vec<_> = snapshot
+        .predictions
+        .iter()
+        .map(|prediction| vec![
+            amount(prediction.price, 2),
+            amount(prediction.short, 4),
+            amount(prediction.long, 4),
+            amount(prediction.short + prediction.long, 4),
+        ])
+        .collect();
+    let title = vec!["ETH $".cell().bold(true), "Short eth".cell().bold(true), "Long eth".cell().bold(true), "Total eth".cell().bold(true)];
+    print_stdout(predictions.table().title(title))?;
+    Ok(())
+}
+
+/// The strategy of the profile, with the values given on the command line.
+fn strategy_from_args(sub_m: &clap::ArgMatches<'_>, mut strategy: StrategyConfig) -> Result<StrategyConfig, Box<dyn Error>> {
+    if let Some(target_ratio) = sub_m.value_of("TARGET_RATIO") {
+        strategy.target_ratio = target_ratio.parse()?;
+    }
+    if let Some(keep_ratio) = sub_m.value_of("KEEP_RATIO") {
+        strategy.keep_ratio = keep_ratio.parse()?;
+    }
+    if let Some(short_share) = sub_m.value_of("SHORT_SHARE") {
+        strategy.short_share = short_share.parse()?;
+    }
+    if let Some(long_share) = sub_m.value_of("LONG_SHARE") {
+        strategy.long_share = Some(long_share.parse()?);
+    }
+    Ok(strategy)
+}
+
+async fn run_backtest(sub_m: &clap::ArgMatches<'_>, node: &str, feed: Address, strategy: &StrategyConfig) -> Result<(), Box<dyn Error>> {
+    let prices = match sub_m.value_of("CSV") {
+        Some(path) => parse_price_csv(&fs::read_to_string(path)?)?,
+        None => {
+            let from = parse_timestamp(sub_m.value_of("FROM").ok_or("give the start of the history with --from, or a --csv file")?)?;
+            let to = match sub_m.value_of("TO") {
+                Some(to) => parse_end_timestamp(to)?,
+                None => u64::MAX,
+            };
+            let reader = HttpBlockchainReader::new(HttpTransport::new(node))?;
+            let mut cache = match RoundCache::default_path(&feed) {
+                Some(path) => RoundCache::open(&path)?,
+                None => RoundCache::in_memory(),
+            };
+            Chainlink::at(&reader, feed)?.get_history(from, to, &mut cache).await?
+        }
+    };
+    let initial_eth: f64 = sub_m.value_of("VALUE").unwrap().parse()?;
+    let cost_ratio: f64 = sub_m.value_of("COST").unwrap().parse()?;
+    let result = backtest(&prices, initial_eth, strategy, cost_ratio)?;
+
+    if let Some(path) = sub_m.value_of("OUTPUT") {
+        fs::write(path, result.to_csv())?;
+    }
+
+    println!("{} prices, {} rebalances costing {:.2} $", result.points.len(), result.rebalances, result.costs_usd);
+    println!("Bermuda: {:.2} $, max drawdown {:.1}%", result.final_value_usd(), result.max_drawdown * 100.0);
+    println!("Hold eth: {:.2} $, max drawdown {:.1}%", result.hold_final_value_usd(), result.hold_max_drawdown * 100.0);
+    Ok(())
+}
+
+/// Redraw the portfolio every `--interval` seconds, or on every new block with `--blocks`,
+/// until interrupted, record it in the history and notify the alerts of the profile. Errors are
+/// shown in place of the portfolio and the watch goes on.
+async fn run_watch(sub_m: &clap::ArgMatches<'_>, node: &str, smart_wallet: &str, config: &PortfolioConfig, profile: &Profile) -> Result<(), Box<dyn Error>> {
+    let on_blocks = sub_m.is_present("BLOCKS");
+    let interval = match sub_m.value_of("INTERVAL") {
+        Some(interval) => match interval.parse()? {
+            0 => return Err("the interval must be at least one second".into()),
+            seconds => Duration::from_secs(seconds),
+        },
+        None if on_blocks => Duration::from_secs(4),
+        None => Duration::from_secs(60),
+    };
+    let reader = HttpBlockchainReader::new(HttpTransport::new(node))?;
+    let mut filter = match on_blocks {
+        true => Some(reader.new_block_filter().await?),
+        false => None,
+    };
+    let mut engine = profile.alerts.as_ref().map(AlertEngine::from_config).transpose()?;
+    let store = open_store(profile);
+    let mut previous: Option<WatchValues> = None;
+    loop {
+        let portfolio = load_latest(&reader, node, smart_wallet, config).await;
+        // clear the screen and go back to its top left corner
+        print!("\x1b[2J\x1b[H");
+        match &portfolio {
+            Ok(portfolio) => {
+                let values = WatchValues::from(&portfolio.snapshot);
+                print!("{}", render_watch(&values, previous.as_ref()));
+                previous = Some(values);
+                if let Some(store) = store.as_ref() {
+                    record(store, portfolio, smart_wallet);
+                }
+            }
+            Err(error) => println!("error: {}", error),
+        }
+        if let Some(engine) = engine.as_mut() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let (alerts, failures) = engine.refresh(portfolio.as_ref(), now).await;
+            println!();
+            for alert in alerts.iter() {
+                println!("alert sent: {}", alert.message);
+            }
+            for failure in failures.iter() {
+                println!("error: {}", failure);
+            }
+        }
+        io::stdout().flush()?;
+
+        match filter.as_mut() {
+            None => tokio::time::delay_for(interval).await,
+            Some(filter) => loop {
+                tokio::time::delay_for(interval).await;
+                match reader.get_filter_changes(filter).await {
+                    Ok(blocks) if blocks.is_empty() => continue,
+                    Ok(_) => break,
+                    // nodes drop the filters that are not polled for a while, a new one is
+                    // installed and the node asked again on the next tick when it fails too
+                    Err(_) => match reader.new_block_filter().await {
+                        Ok(installed) => *filter = installed,
+                        Err(error) => {
+                            println!("error: {}", error);
+                            io::stdout().flush()?;
+                        }
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// The portfolio at the latest block, every read pinned to it.
+async fn load_latest(reader: &HttpBlockchainReader, node: &str, smart_wallet: &str, config: &PortfolioConfig) -> Result<Portfolio, BermudaError> {
+    let block_number = reader.resolve_block(BlockTag::Latest).await?;
+    let reader = HttpBlockchainReader::new(HttpTransport::new(node))?.at_block(BlockTag::Number(block_number));
+    Portfolio::load(&reader, smart_wallet, config).await
+}
 
 const TEMPLATE: &str = include_str!("templates/index.html");
 
+/// The snapshot history of the profile, None with a warning when it cannot be opened: the
+/// portfolio is still shown.
+fn open_store(profile: &Profile) -> Option<SnapshotStore> {
+    let path = profile.history.clone().or_else(SnapshotStore::default_path)?;
+    SnapshotStore::open(&path)
+        .map_err(|error| eprintln!("warning: {}", error))
+        .ok()
+}
+
+fn record(store: &SnapshotStore, portfolio: &Portfolio, smart_wallet: &str) {
+    if let Err(error) = store.append(&StoredSnapshot::from_portfolio(portfolio, smart_wallet)) {
+        eprintln!("warning: {}", error);
+    }
+}
+
+/// List or export the recorded snapshots of the smart wallet between `--from` and `--to`.
+fn run_history(sub_m: &clap::ArgMatches<'_>, profile: &Profile) -> Result<(), Box<dyn Error>> {
+    let path = match sub_m.value_of("DB") {
+        Some(path) => PathBuf::from(path),
+        None => profile
+            .history
+            .clone()
+            .or_else(SnapshotStore::default_path)
+            .ok_or("no snapshot history, give one with --db")?,
+    };
+    if !path.exists() {
+        return Err(format!("no snapshot history at {}, the commands reading the portfolio record it", path.display()).into());
+    }
+    let store = SnapshotStore::open(&path)?;
+    let smart_wallet = match sub_m.is_present("ALL") {
+        true => None,
+        false => sub_m.value_of("SMART_WALLET").or(profile.smart_wallet.as_deref()),
+    };
+    let from = sub_m.value_of("FROM").map(parse_timestamp).transpose()?.unwrap_or(0);
+    let to = sub_m.value_of("TO").map(parse_end_timestamp).transpose()?.unwrap_or(u64::MAX);
+    let snapshots = store.list(smart_wallet, from, to)?;
 
+    let output = match sub_m.value_of("FORMAT") {
+        Some("json") => format!("{}\n", serde_json::to_string_pretty(&snapshots)?),
+        Some("csv") => {
+            let mut csv = String::from(StoredSnapshot::csv_header());
+            for snapshot in snapshots.iter() {
+                csv.push_str(&snapshot.to_csv());
+            }
+            csv
+        }
+        _ => {
+            let mut text = String::new();
+            for snapshot in snapshots.iter() {
+                // with --all, the snapshots of every smart wallet are mixed
+                if smart_wallet.is_none() {
+                    text.push_str(&format!("0x{}  ", snapshot.smart_wallet));
+                }
+                text.push_str(&format!(
+                    "{}  block {}  {:.2} $  short {:.2} eth  long {:.2} eth  total {:.2} eth ({:.2} $)\n",
+                    format_timestamp(snapshot.timestamp),
+                    snapshot.block,
+                    snapshot.eth_price,
+                    snapshot.short,
+                    snapshot.long,
+                    snapshot.total,
+                    snapshot.total * snapshot.eth_price
+                ));
+            }
+            text.push_str(&format!("{} snapshots\n", snapshots.len()));
+            text
+        }
+    };
+    match sub_m.value_of("OUTPUT") {
+        Some(path) => fs::write(path, output)?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
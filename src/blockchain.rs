@@ -1,33 +1,336 @@
+use super::amount::Amount;
+use super::decode::{decode_revert, parse_address, revert_reason};
+use super::error::{BermudaError, Result};
+use super::multicall::{decode_aggregate3, load_multicall3, Batch, MULTICALL3_ADDRESS};
+use super::transaction::UnsignedTransaction;
 use async_jsonrpc_client::HttpTransport;
-use async_jsonrpc_client::{Params, Transport, Value};
-use ethabi::{Address, Contract, Token, Uint};
+use async_trait::async_trait;
+use async_jsonrpc_client::{BatchTransport, Params, Transport, Value};
+use ethabi::{Address, Contract, Hash, Token, Uint};
 use rustc_hex::FromHex;
-use rustc_hex::FromHexError;
+use serde::Serialize;
 use serde_json::json;
-use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
 use web3::types::Bytes;
 use web3::types::CallRequest;
 
-pub struct HttpBlockchainReader {
-    transport: HttpTransport,
+/// The block at which the chain state is read.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BlockTag {
+    #[default]
+    Latest,
+    Safe,
+    Finalized,
+    Number(u64),
+    Hash(Hash),
 }
 
-impl HttpBlockchainReader {
-    pub fn new(transport: HttpTransport) -> Result<Self, Box<dyn Error>> {
-        Ok(Self { transport })
+impl BlockTag {
+    /// The block parameter as expected by `eth_call`, `eth_getBalance` and `eth_getStorageAt`.
+    /// Block hashes use the EIP-1898 object form.
+    pub fn to_param(&self) -> Value {
+        match self {
+            BlockTag::Latest => json!("latest"),
+            BlockTag::Safe => json!("safe"),
+            BlockTag::Finalized => json!("finalized"),
+            BlockTag::Number(number) => json!(format!("{:#x}", number)),
+            BlockTag::Hash(hash) => json!({ "blockHash": format!("{:#x}", hash) }),
+        }
+    }
+}
+
+impl fmt::Display for BlockTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockTag::Latest => write!(f, "latest"),
+            BlockTag::Safe => write!(f, "safe"),
+            BlockTag::Finalized => write!(f, "finalized"),
+            BlockTag::Number(number) => write!(f, "{}", number),
+            BlockTag::Hash(hash) => write!(f, "{:#x}", hash),
+        }
+    }
+}
+
+impl FromStr for BlockTag {
+    type Err = BermudaError;
+
+    /// Parse `latest`, `safe`, `finalized`, a decimal or `0x` block number, or a block hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BermudaError::Input(format!("invalid block: {}", s));
+        match s {
+            "latest" => Ok(BlockTag::Latest),
+            "safe" => Ok(BlockTag::Safe),
+            "finalized" => Ok(BlockTag::Finalized),
+            _ => match s.strip_prefix("0x") {
+                Some(hex) if hex.len() == 64 => {
+                    Ok(BlockTag::Hash(hex.parse().map_err(|_| invalid())?))
+                }
+                Some(hex) => Ok(BlockTag::Number(
+                    u64::from_str_radix(hex, 16).map_err(|_| invalid())?,
+                )),
+                None => Ok(BlockTag::Number(s.parse().map_err(|_| invalid())?)),
+            },
+        }
+    }
+}
+
+/// A mined transaction.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TransactionReceipt {
+    pub transaction_hash: Hash,
+    pub block_number: u64,
+    pub gas_used: u64,
+    /// False when the transaction reverted.
+    pub success: bool,
+}
+
+/// Access to the chain state, at the block returned by `block`.
+///
+/// Implementations only provide the raw JSON-RPC methods; ABI encoding, decoding and batching
+/// are shared by every transport. Transactions are only sent by `Simulator`, to local forks.
+#[async_trait(?Send)]
+pub trait BlockchainReader {
+    /// The block every read is made at.
+    fn block(&self) -> BlockTag;
+
+    /// The number of the block every read is made at.
+    async fn block_number(&self) -> Result<u64>;
+
+    /// The unix timestamp of the block every read is made at.
+    async fn block_timestamp(&self) -> Result<u64>;
+
+    /// `eth_call` the contract with the ABI encoded `data`, returning the raw output.
+    async fn eth_call(&self, contract_address: &Address, data: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// `eth_getBalance` of the address, in wei.
+    async fn eth_get_balance(&self, address: &Address) -> Result<Uint>;
+
+    /// `eth_getStorageAt`, the whole 32 bytes word.
+    async fn eth_get_storage_at(&self, address: &Address, position: Uint) -> Result<Vec<u8>>;
+
+    /// `eth_gasPrice`, in wei.
+    async fn eth_gas_price(&self) -> Result<Uint>;
+
+    /// `eth_chainId`.
+    async fn eth_chain_id(&self) -> Result<u64>;
+
+    /// `eth_call` the transaction as its sender would send it, returning the raw output.
+    async fn eth_call_transaction(&self, transaction: &UnsignedTransaction) -> Result<Vec<u8>>;
+
+    /// `eth_sendTransaction`, from an unlocked or impersonated sender. Returns its hash.
+    async fn eth_send_transaction(&self, transaction: &UnsignedTransaction) -> Result<Hash>;
+
+    /// `eth_getTransactionReceipt`, None until the transaction is mined.
+    async fn eth_get_transaction_receipt(&self, hash: &Hash) -> Result<Option<TransactionReceipt>>;
+
+    /// Let `eth_send_transaction` send from `address` without its key, on an anvil or
+    /// hardhat fork.
+    async fn impersonate_account(&self, address: &Address) -> Result<()>;
+
+    async fn stop_impersonating_account(&self, address: &Address) -> Result<()>;
+
+    /// Run several `eth_call` at once, returning the raw output or the failure of each call.
+    /// Calls are sent one after the other unless the transport can do better.
+    async fn eth_call_batch(
+        &self,
+        requests: Vec<(Address, Vec<u8>)>,
+    ) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut results = Vec::new();
+        for (address, data) in requests {
+            results.push(self.eth_call(&address, data).await);
+        }
+        Ok(results)
     }
 
-    pub async fn call_function(
+    async fn call_function(
         &self,
         contract: &Contract,
         contract_address: &Address,
         name: &str,
         params: &[Token],
-    ) -> Result<Vec<Token>, Box<dyn Error>> {
+    ) -> Result<Vec<Token>> {
         let function = contract.function(name)?;
         let data = function.encode_input(params)?;
+        let data = self.eth_call(contract_address, data).await?;
+        let result = function.decode_output(&data)?;
+        Ok(result)
+    }
 
+    /// Run every call of the batch in a single Multicall3 `aggregate3` call, or through
+    /// `eth_call_batch` when Multicall3 is not deployed at the block. A revert of `aggregate3`
+    /// and any other failure of the node are returned as is.
+    /// Returns the decoded output or the failure of each call, in the order they were added.
+    async fn call_batch(
+        &self,
+        batch: &Batch<'_>,
+    ) -> Result<Vec<Result<Vec<Token>>>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        let multicall = load_multicall3()?;
+        let multicall_address = parse_address(MULTICALL3_ADDRESS)?;
+        let data = batch.encode_aggregate3(&multicall)?;
+        let results = match self.eth_call(&multicall_address, data).await {
+            Ok(data) if !data.is_empty() => decode_aggregate3(&multicall, &data)?
+                .into_iter()
+                .enumerate()
+                .map(|(index, (success, data))| match success {
+                    true => batch.decode_output(index, &data),
+                    false => Err(BermudaError::Revert {
+                        contract: *batch.address(index),
+                        reason: revert_reason(&data),
+                    }),
+                })
+                .collect(),
+            // calling an address without code succeeds with an empty output
+            Ok(_) => {
+                let requests = batch
+                    .requests()
+                    .map(|(address, data)| (*address, data.to_vec()))
+                    .collect();
+                self.eth_call_batch(requests)
+                    .await?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, result)| batch.decode_output(index, &result?))
+                    .collect()
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(results)
+    }
+
+    async fn get_storage_at(
+        &self,
+        address: &Address,
+        position: Uint,
+        length: usize,
+    ) -> Result<Vec<u8>> {
+        let data = self.eth_get_storage_at(address, position).await?;
+        let data: Vec<u8> = data.iter().rev().take(length).rev().cloned().collect();
+        Ok(data)
+    }
+
+    async fn get_eth_balance(&self, address: &Address) -> Result<Amount> {
+        let balance = self.eth_get_balance(address).await?;
+        Ok(Amount::from_wei(balance))
+    }
+}
+
+pub struct HttpBlockchainReader {
+    transport: HttpTransport,
+    block: BlockTag,
+    /// Contracts whose custom errors are decoded in the revert reasons.
+    errors: Vec<Contract>,
+}
+
+impl HttpBlockchainReader {
+    pub fn new(transport: HttpTransport) -> Result<Self> {
+        Ok(Self {
+            transport,
+            block: BlockTag::Latest,
+            errors: vec![Contract::load(&include_bytes!("abi/comet.abi")[..])?],
+        })
+    }
+
+    /// Read every subsequent call at `block` instead of `latest`.
+    pub fn at_block(self, block: BlockTag) -> Self {
+        Self { block, ..self }
+    }
+
+    /// Resolve a block tag to the number of the block it currently designates.
+    pub async fn resolve_block(&self, block: BlockTag) -> Result<u64> {
+        match block {
+            BlockTag::Number(number) => Ok(number),
+            _ => block_field(&self.get_block(block).await?, block, "number"),
+        }
+    }
+
+    /// Install an `eth_newBlockFilter`, returning its id.
+    pub async fn new_block_filter(&self) -> Result<String> {
+        let response: Value = self
+            .transport
+            .send("eth_newBlockFilter", Params::Array(vec![]))
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        response.as_str().map(|id| id.to_string()).ok_or_else(|| {
+            BermudaError::Transport(format!("expected a filter id, got {}", response))
+        })
+    }
+
+    /// The hashes of the blocks mined since the filter was last polled.
+    pub async fn get_filter_changes(&self, filter_id: &str) -> Result<Vec<Hash>> {
+        let response: Vec<String> = self
+            .transport
+            .send("eth_getFilterChanges", Params::Array(vec![json!(filter_id)]))
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        response.iter().map(|hash| parse_hash(hash)).collect()
+    }
+
+    /// The `eth_getBlockByNumber` or `eth_getBlockByHash` header of the block.
+    async fn get_block(&self, block: BlockTag) -> Result<Value> {
+        let response: Value = match block {
+            BlockTag::Hash(hash) => {
+                let params = Params::Array(vec![json!(format!("{:#x}", hash)), json!(false)]);
+                self.transport
+                    .send("eth_getBlockByHash", params)
+                    .await
+                    .map_err(BermudaError::from_rpc)?
+            }
+            BlockTag::Number(_) | BlockTag::Latest | BlockTag::Safe | BlockTag::Finalized => {
+                let params = Params::Array(vec![block.to_param(), json!(false)]);
+                self.transport
+                    .send("eth_getBlockByNumber", params)
+                    .await
+                    .map_err(BermudaError::from_rpc)?
+            }
+        };
+        match response.is_null() {
+            true => Err(BermudaError::Input(format!("block {} is not known by the node", block))),
+            false => Ok(response),
+        }
+    }
+
+    /// Call the `anvil_` method, or the `hardhat_` one when the node is not an anvil.
+    async fn fork_method(&self, method: &str, address: &Address) -> Result<()> {
+        let params = Params::Array(vec![json!(format!("{:#x}", address))]);
+        if self.transport.send::<_, Value>(format!("anvil_{}", method), params.clone()).await.is_ok() {
+            return Ok(());
+        }
+        self.transport
+            .send::<_, Value>(format!("hardhat_{}", method), params)
+            .await
+            .map_err(|error| BermudaError::Input(format!(
+                "cannot {} {:#x}, is the node an anvil or hardhat fork? ({})",
+                method, address, error
+            )))?;
+        Ok(())
+    }
+
+    /// The revert of `contract` from the error object of a failed call, with its `data`
+    /// decoded when the node gives it.
+    fn revert<E: fmt::Display>(&self, error: E, contract: &Address) -> BermudaError {
+        let message = error.to_string();
+        let data = message
+            .find('{')
+            .and_then(|start| serde_json::from_str::<Value>(&message[start..]).ok())
+            .and_then(|object| {
+                // hardhat nests the revert data in an object
+                let data = object["data"].as_str().or_else(|| object["data"]["data"].as_str())?;
+                data.trim_start_matches("0x").from_hex::<Vec<u8>>().ok()
+            });
+        match data {
+            Some(data) if !data.is_empty() => BermudaError::Revert {
+                contract: *contract,
+                reason: decode_revert(&data, &self.errors),
+            },
+            _ => BermudaError::from_rpc(message).into_revert(contract),
+        }
+    }
+
+    fn call_params(&self, contract_address: &Address, data: Vec<u8>) -> Result<Params> {
         let req = serde_json::to_value(CallRequest {
             from: None,
             to: Some(web3::types::Address::from_slice(&contract_address.to_fixed_bytes())),
@@ -36,71 +339,269 @@ impl HttpBlockchainReader {
             value: None,
             data: Some(Bytes(data)),
         })?;
-        let params = Params::Array(vec![req, json!("latest")]);
-        let response: Value = self.transport.send("eth_call", params).await?;
-        let hex_str = &response.as_str().ok_or(BlockchainError(String::from(
-            "cannot retrieve response from eth_call",
-        )))?[2..];
-        let data: Vec<u8> = hex_str.from_hex()?;
-        let result = function.decode_output(&data)?;
-        Ok(result)
+        Ok(Params::Array(vec![req, self.block.to_param()]))
     }
+}
 
-    pub async fn get_storage_at(
-        &self,
-        address: &Address,
-        position: Uint,
-        length: usize,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
+#[async_trait(?Send)]
+impl BlockchainReader for HttpBlockchainReader {
+    fn block(&self) -> BlockTag {
+        self.block
+    }
+
+    async fn block_number(&self) -> Result<u64> {
+        self.resolve_block(self.block).await
+    }
+
+    async fn block_timestamp(&self) -> Result<u64> {
+        block_field(&self.get_block(self.block).await?, self.block, "timestamp")
+    }
+
+    async fn eth_call(&self, contract_address: &Address, data: Vec<u8>) -> Result<Vec<u8>> {
+        let params = self.call_params(contract_address, data)?;
+        let response: Value = self
+            .transport
+            .send("eth_call", params)
+            .await
+            .map_err(|error| self.revert(error, contract_address))?;
+        decode_hex_response(&response)
+    }
+
+    async fn eth_get_balance(&self, address: &Address) -> Result<Uint> {
         let params = Params::Array(vec![
             Value::String(format!("{:#x}", address)),
-            Value::String(format!("{:#x}", position)),
-            Value::String("latest".to_string()),
+            self.block.to_param(),
         ]);
-        let response: Value = self.transport.send("eth_getStorageAt", params).await?;
-        let hex_str = &response.as_str().ok_or(BlockchainError(String::from(
-            "cannot retrieve response from eth_call",
-        )))?[2..];
-        let data: Vec<u8> = hex_str.from_hex()?;
-        let data: Vec<u8> = data.iter().rev().take(length).rev().cloned().collect();
-        Ok(data)
+        let response: Value = self
+            .transport
+            .send("eth_getBalance", params)
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        let hex_str = response.as_str().ok_or_else(|| {
+            BermudaError::Transport(String::from("cannot retrieve response from eth_getBalance"))
+        })?;
+        Uint::from_str_radix(hex_str.trim_start_matches("0x"), 16)
+            .map_err(|_| BermudaError::Transport(format!("invalid balance {}", hex_str)))
     }
 
-    pub async fn get_eth_balance(&self, address: &Address) -> Result<f64, Box<dyn Error>> {
+    async fn eth_get_storage_at(&self, address: &Address, position: Uint) -> Result<Vec<u8>> {
         let params = Params::Array(vec![
             Value::String(format!("{:#x}", address)),
-            Value::String("latest".to_string()),
+            Value::String(format!("{:#x}", position)),
+            self.block.to_param(),
         ]);
-        let response: Value = self.transport.send("eth_getBalance", params).await?;
-        let hex_str = &response.as_str().ok_or(BlockchainError(String::from(
-            "cannot retrieve response from eth_call",
-        )))?[2..];
-        let result: Result<Vec<u8>, FromHexError> = hex_str.from_hex();
-        let data = match result {
-            Ok(data) => data,
-            Err(_) => {
-                let new_hex_str = ["0", hex_str].concat();
-                new_hex_str.from_hex()?
-            }
-        };
-        let data: Vec<u8> = data.iter().rev().take(16).rev().cloned().collect();
-        let eth = Uint::from_big_endian(&data).as_u128() as f64 / Uint::exp10(18).as_u128() as f64;
+        let response: Value = self
+            .transport
+            .send("eth_getStorageAt", params)
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        decode_hex_response(&response)
+    }
 
-        Ok(eth)
+    async fn eth_gas_price(&self) -> Result<Uint> {
+        let response: Value = self
+            .transport
+            .send("eth_gasPrice", Params::Array(vec![]))
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        let hex_str = response.as_str().ok_or_else(|| {
+            BermudaError::Transport(String::from("cannot retrieve response from eth_gasPrice"))
+        })?;
+        Uint::from_str_radix(hex_str.trim_start_matches("0x"), 16)
+            .map_err(|_| BermudaError::Transport(format!("invalid gas price {}", hex_str)))
+    }
+
+    async fn eth_chain_id(&self) -> Result<u64> {
+        let response: Value = self
+            .transport
+            .send("eth_chainId", Params::Array(vec![]))
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        let hex_str = response.as_str().ok_or_else(|| {
+            BermudaError::Transport(String::from("cannot retrieve response from eth_chainId"))
+        })?;
+        u64::from_str_radix(hex_str.trim_start_matches("0x"), 16)
+            .map_err(|_| BermudaError::Transport(format!("invalid chain id {}", hex_str)))
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct BlockchainError(pub String);
+    async fn eth_call_transaction(&self, transaction: &UnsignedTransaction) -> Result<Vec<u8>> {
+        let params = Params::Array(vec![serde_json::to_value(transaction)?, self.block.to_param()]);
+        let response: Value = self
+            .transport
+            .send("eth_call", params)
+            .await
+            .map_err(|error| self.revert(error, &transaction.to))?;
+        decode_hex_response(&response)
+    }
 
-impl fmt::Display for BlockchainError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+    async fn eth_send_transaction(&self, transaction: &UnsignedTransaction) -> Result<Hash> {
+        let response: Value = self
+            .transport
+            .send("eth_sendTransaction", Params::Array(vec![serde_json::to_value(transaction)?]))
+            .await
+            .map_err(|error| self.revert(error, &transaction.to))?;
+        let hash = response.as_str().ok_or_else(|| {
+            BermudaError::Transport(format!("expected a transaction hash, got {}", response))
+        })?;
+        parse_hash(hash)
     }
+
+    async fn eth_get_transaction_receipt(&self, hash: &Hash) -> Result<Option<TransactionReceipt>> {
+        let receipt: Value = self
+            .transport
+            .send("eth_getTransactionReceipt", Params::Array(vec![json!(format!("{:#x}", hash))]))
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        match receipt.is_null() {
+            true => Ok(None),
+            false => parse_receipt(&receipt).map(Some),
+        }
+    }
+
+    async fn impersonate_account(&self, address: &Address) -> Result<()> {
+        self.fork_method("impersonateAccount", address).await
+    }
+
+    async fn stop_impersonating_account(&self, address: &Address) -> Result<()> {
+        self.fork_method("stopImpersonatingAccount", address).await
+    }
+
+    /// Send all the calls in a single JSON-RPC batch request.
+    async fn eth_call_batch(
+        &self,
+        requests: Vec<(Address, Vec<u8>)>,
+    ) -> Result<Vec<Result<Vec<u8>>>> {
+        let requests = requests
+            .into_iter()
+            .map(|(address, data)| Ok(("eth_call", self.call_params(&address, data)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let responses = self
+            .transport
+            .send_batch(requests)
+            .await
+            .map_err(BermudaError::from_rpc)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| response.map_err(BermudaError::from_rpc).and_then(|value| decode_hex_response(&value)))
+            .collect())
+    }
+}
+
+/// A quantity of a block header, such as its `number` or `timestamp`.
+fn block_field(header: &Value, block: BlockTag, field: &str) -> Result<u64> {
+    let value = header[field].as_str().ok_or_else(|| {
+        BermudaError::Transport(format!("block {} has no {}", block, field))
+    })?;
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| BermudaError::Transport(format!("invalid block {} {}", field, value)))
+}
+
+fn parse_receipt(receipt: &Value) -> Result<TransactionReceipt> {
+    let field = |name: &str| {
+        receipt[name]
+            .as_str()
+            .ok_or_else(|| BermudaError::Transport(format!("the receipt has no {}", name)))
+    };
+    let quantity = |name: &str| {
+        let value = field(name)?;
+        u64::from_str_radix(value.trim_start_matches("0x"), 16)
+            .map_err(|_| BermudaError::Transport(format!("invalid receipt {} {}", name, value)))
+    };
+    Ok(TransactionReceipt {
+        transaction_hash: parse_hash(field("transactionHash")?)?,
+        block_number: quantity("blockNumber")?,
+        gas_used: quantity("gasUsed")?,
+        success: quantity("status")? != 0,
+    })
 }
 
-impl Error for BlockchainError {
-    fn description(&self) -> &str {
-        &self.0
+fn parse_hash(hash: &str) -> Result<Hash> {
+    hash.trim_start_matches("0x")
+        .parse()
+        .map_err(|_| BermudaError::Transport(format!("invalid hash {}", hash)))
+}
+
+fn decode_hex_response(response: &Value) -> Result<Vec<u8>> {
+    let hex_str = response.as_str().ok_or_else(|| {
+        BermudaError::Transport(format!("expected an hex string, got {}", response))
+    })?;
+    hex_str
+        .trim_start_matches("0x")
+        .from_hex()
+        .map_err(|_| BermudaError::Transport(format!("invalid hex string {}", hex_str)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+    use test_case::test_case;
+
+    #[test_case("latest", BlockTag::Latest ; "latest")]
+    #[test_case("finalized", BlockTag::Finalized ; "finalized")]
+    #[test_case("16000000", BlockTag::Number(16_000_000) ; "decimal number")]
+    #[test_case("0xf42400", BlockTag::Number(16_000_000) ; "hex number")]
+    fn block_tag_parse_tests(input: &str, expected: BlockTag) {
+        assert_eq!(input.parse::<BlockTag>().unwrap(), expected);
+        assert_eq!(expected.to_string().parse::<BlockTag>().unwrap(), expected);
+    }
+
+    #[test]
+    fn block_tag_hash_param() {
+        let hash = "0x88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6";
+        let block: BlockTag = hash.parse().unwrap();
+        assert_eq!(block.to_param(), json!({ "blockHash": hash }));
+    }
+
+    #[test]
+    fn revert_from_error_object() {
+        let reader = HttpBlockchainReader::new(HttpTransport::new("http://localhost:8545")).unwrap();
+        let contract = Address::from_low_u64_be(7);
+        let data = reader.errors[0].error("Paused").unwrap().encode(&[]).unwrap();
+        let error = format!(
+            r#"rpc error: {{"code":3,"message":"execution reverted","data":"0x{}"}}"#,
+            rustc_hex::ToHex::to_hex::<String>(&data[..])
+        );
+        assert_eq!(
+            reader.revert(error, &contract),
+            BermudaError::Revert { contract, reason: Some(String::from("Paused()")) }
+        );
+        assert_eq!(
+            reader.revert(r#"rpc error: {"code":-32000,"message":"execution reverted: 59"}"#, &contract),
+            BermudaError::Revert { contract, reason: Some(String::from("59")) }
+        );
+    }
+
+    #[test]
+    fn parse_receipt_tests() {
+        let hash = format!("{:#x}", Hash::from_low_u64_be(1));
+        let receipt = json!({ "transactionHash": hash, "status": "0x1", "blockNumber": "0x10", "gasUsed": "0x5208" });
+        let receipt = parse_receipt(&receipt).unwrap();
+        assert_eq!((receipt.block_number, receipt.gas_used, receipt.success), (16, 21_000, true));
+
+        let reverted = json!({ "transactionHash": hash, "status": "0x0", "blockNumber": "0x10", "gasUsed": "0x5208" });
+        assert!(!parse_receipt(&reverted).unwrap().success);
+        assert!(parse_receipt(&json!({ "status": "0x1" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn call_batch_only_falls_back_without_multicall() {
+        let erc20 = Contract::load(&include_bytes!("abi/erc20.abi")[..]).unwrap();
+        let token = Address::from_low_u64_be(7);
+        let mut batch = Batch::new();
+        batch.add(&erc20, &token, "decimals", &[]).unwrap();
+        let mut reader = MockBlockchainReader::new();
+        reader.mock_call(&erc20, &token, "decimals", &[], &[Token::Uint(Uint::from(6))]).unwrap();
+        // no code at the Multicall3 address
+        assert_eq!(reader.call_batch(&batch).await.unwrap(), vec![Ok(vec![Token::Uint(Uint::from(6))])]);
+
+        let multicall_address = parse_address(MULTICALL3_ADDRESS).unwrap();
+        let data = batch.encode_aggregate3(&load_multicall3().unwrap()).unwrap();
+        reader.mock_revert(&multicall_address, data, vec![]);
+        assert!(matches!(reader.call_batch(&batch).await, Err(BermudaError::Revert { .. })));
+
+        let unreachable = BermudaError::Transport(String::from("connection refused"));
+        reader.mock_node_error(unreachable.clone());
+        assert_eq!(reader.call_batch(&batch).await, Err(unreachable));
     }
 }
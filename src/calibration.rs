@@ -0,0 +1,83 @@
+use super::error::{BermudaError, Result};
+use super::Prediction;
+
+/// The exponent of `predict` for loans kept exactly at `ratio` (collateral / debt).
+///
+/// A short leg at ratio r has an equity of `debt * (r - 1)` eth, and keeping r constant
+/// makes that equity scale with `price^(-r / (r - 1))`. The long leg mirrors it in $.
+pub fn exponent_for_ratio(ratio: f64) -> Result<f64> {
+    if ratio <= 1.0 {
+        return Err(BermudaError::Model(format!("a collateral ratio of {} is already liquidated", ratio)));
+    }
+    Ok(ratio / (ratio - 1.0))
+}
+
+/// The effective exponent of loans left to drift between the `low` and `high` collateral
+/// ratios before being rebalanced: the equity goes from `debt * (high - 1)` to
+/// `debt * (low - 1)` while the price moves by `high / low`.
+pub fn exponent_for_band(low: f64, high: f64) -> Result<f64> {
+    if low > high {
+        return exponent_for_band(high, low);
+    }
+    if low <= 1.0 {
+        return Err(BermudaError::Model(format!("a collateral ratio of {} is already liquidated", low)));
+    }
+    if high - low < 1e-9 {
+        return exponent_for_ratio(low);
+    }
+    Ok(((high - 1.0) / (low - 1.0)).ln() / (high / low).ln())
+}
+
+/// Fit the exponent to recorded snapshots with a log-log regression sharing the slope
+/// between both legs: `ln(short) = a - k ln(price)` and `ln(long * price) = b + k ln(price)`.
+pub fn fit_exponent(snapshots: &[Prediction]) -> Result<f64> {
+    let usable: Vec<&Prediction> = snapshots
+        .iter()
+        .filter(|snapshot| snapshot.price > 0.0 && snapshot.short > 0.0 && snapshot.long > 0.0)
+        .collect();
+    if usable.len() < 2 {
+        return Err(BermudaError::Model("at least 2 snapshots are needed to fit the exponent".to_string()));
+    }
+    let n = usable.len() as f64;
+    let x: Vec<f64> = usable.iter().map(|snapshot| snapshot.price.ln()).collect();
+    let x_mean = x.iter().sum::<f64>() / n;
+    let variance: f64 = x.iter().map(|x| (x - x_mean).powi(2)).sum();
+    if variance == 0.0 {
+        return Err(BermudaError::Model("the snapshots must be taken at different prices".to_string()));
+    }
+    let short: Vec<f64> = usable.iter().map(|snapshot| snapshot.short.ln()).collect();
+    let long: Vec<f64> = usable.iter().map(|snapshot| (snapshot.long * snapshot.price).ln()).collect();
+    let covariance = |y: &[f64]| {
+        let y_mean = y.iter().sum::<f64>() / n;
+        x.iter().zip(y).map(|(x, y)| (x - x_mean) * (y - y_mean)).sum::<f64>()
+    };
+    Ok((covariance(&long) - covariance(&short)) / (2.0 * variance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predict;
+    use test_case::test_case;
+
+    #[test_case(1.5, 1.5, 3.0 ; "constant ratio")]
+    #[test_case(2.0, 2.0, 2.0 ; "lower leverage")]
+    #[test_case(1.3, 1.7, 3.16 ; "band")]
+    #[test_case(1.7, 1.3, 3.16 ; "reversed band")]
+    fn analytic_exponent_tests(low: f64, high: f64, expected: f64) {
+        let exponent = exponent_for_band(low, high).unwrap();
+        assert_eq!((exponent * 100.0).round() / 100.0, expected);
+    }
+
+    #[test]
+    fn fit_recovers_the_model_exponent() {
+        let start = Prediction { price: 2000.0, short: 10.0, long: 5.0 };
+        let snapshots: Vec<Prediction> = [1000.0, 1500.0, 2500.0, 4000.0]
+            .iter()
+            .map(|price| predict(&start, *price, 2.6).unwrap())
+            .collect();
+        assert_eq!((fit_exponent(&snapshots).unwrap() * 1000.0).round(), 2600.0);
+        assert!(fit_exponent(&snapshots[..1]).is_err());
+        assert!(exponent_for_ratio(1.0).is_err());
+    }
+}
@@ -1,42 +1,615 @@
-use super::blockchain::HttpBlockchainReader;
-use ethabi::Uint;
-use ethabi::{Address, Contract};
-use std::error::Error;
-
-const CHAINLINK_ADDRESS: &str = "773616e4d11a78f511299002da57a0a94577f1f4";
-pub struct Chainlink<'a> {
-    blockchain_reader: &'a HttpBlockchainReader,
+use super::blockchain::BlockchainReader;
+use super::amount::Amount;
+use super::decode::{address, int, parse_address, uint};
+use super::error::{BermudaError, Result};
+use super::history::{PricePoint, RoundCache};
+use super::multicall::Batch;
+use ethabi::{Address, Contract, Token, Uint};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The ETH / USD feed.
+pub(crate) const CHAINLINK_ADDRESS: &str = "5f4eC3Df9cbd43714FE2740f5E3616155c5b8419";
+/// The EUR / USD feed.
+pub(crate) const EUR_USD_ADDRESS: &str = "b49f677943BC038e9857d61E7d053CaA2C1734C1";
+/// The ETH / USD feed is updated at least every hour, give it some slack.
+pub const DEFAULT_MAX_AGE: u64 = 3 * 3600;
+/// The slowest feeds, like stETH / ETH, are updated once a day.
+pub const DAILY_MAX_AGE: u64 = 25 * 3600;
+/// How many rounds are read in one batch when walking the history.
+const ROUNDS_PER_BATCH: u64 = 100;
+/// How many consecutive rounds can have no data before the older rounds of a phase are
+/// considered unavailable.
+const MAX_ROUND_GAP: u64 = 10;
+
+/// The latest round of a feed, its answer scaled by the decimals of the feed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PriceReading {
+    pub answer: f64,
+    pub round_id: Uint,
+    /// Unix timestamp of the last update.
+    pub updated_at: u64,
+    /// Seconds between the last update and the block the feed is read at.
+    pub age: u64,
+}
+
+/// What a feed answer must satisfy to be trusted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OracleLimits {
+    /// Maximum age of the answer, in seconds.
+    pub max_age: Option<u64>,
+    pub min_answer: Option<f64>,
+    pub max_answer: Option<f64>,
+}
+
+impl OracleLimits {
+    /// The limits used for the ETH / USD price when none are configured.
+    pub fn eth_usd() -> Self {
+        Self {
+            max_age: Some(DEFAULT_MAX_AGE),
+            ..Self::default()
+        }
+    }
+
+    /// The limits used for the other feeds when none are configured.
+    pub fn daily() -> Self {
+        Self {
+            max_age: Some(DAILY_MAX_AGE),
+            ..Self::default()
+        }
+    }
+}
+
+pub struct Chainlink<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
     chainlink_address: Address,
     chainlink_contract: Contract,
+    limits: OracleLimits,
 }
-impl<'a> Chainlink<'a> {
-    pub fn new(blockchain_reader: &'a HttpBlockchainReader ) -> Result<Self, Box<dyn Error>> {
-        let chainlink_address: Address = CHAINLINK_ADDRESS.parse()?;
+impl<'a, R: BlockchainReader> Chainlink<'a, R> {
+    pub fn new(blockchain_reader: &'a R) -> Result<Self> {
+        Self::at(blockchain_reader, parse_address(CHAINLINK_ADDRESS)?)
+    }
+
+    /// The feed deployed at `chainlink_address`, without any limit.
+    pub fn at(blockchain_reader: &'a R, chainlink_address: Address) -> Result<Self> {
         let chainlink_abi: &[u8] = include_bytes!("abi/chainlink.abi");
         let chainlink_contract: Contract = Contract::load(chainlink_abi)?;
         Ok(Self {
             blockchain_reader,
             chainlink_address,
             chainlink_contract,
+            limits: OracleLimits::default(),
         })
     }
-    pub async fn get_eth_price(&self) -> Result<f64, Box<dyn Error>> {
-        let tokens = self
-            .blockchain_reader
-            .call_function(
-                &self.chainlink_contract,
-                &self.chainlink_address,
-                "latestAnswer",
-                &[],
-            )
-            .await?;
 
-        let price = tokens[0].clone().into_int();
+    /// Reject the answers of the feed outside of `limits`.
+    pub fn with_limits(self, limits: OracleLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// The `latestRoundData` of the feed, checked against the limits.
+    pub async fn get_reading(&self) -> Result<PriceReading> {
+        let mut batch = Batch::new();
+        let round_call = batch.add(&self.chainlink_contract, &self.chainlink_address, "latestRoundData", &[])?;
+        let decimals_call = batch.add(&self.chainlink_contract, &self.chainlink_address, "decimals", &[])?;
+        let mut results = self.blockchain_reader.call_batch(&batch).await?;
+        let decimals = uint(&results.remove(decimals_call)?, 0)?;
+        let round = results.remove(round_call)?;
+        if decimals > Uint::from(77) {
+            return Err(BermudaError::Overflow(format!("{} decimals", decimals)));
+        }
+        let round_id = uint(&round, 0)?;
+        let answer = int(&round, 1)?;
+        let updated_at = uint(&round, 3)?;
+        let answered_in_round = uint(&round, 4)?;
+
+        let stale = |reason: String| BermudaError::StaleOracle {
+            feed: self.chainlink_address,
+            reason,
+        };
+        if updated_at.is_zero() || answered_in_round < round_id {
+            return Err(stale(format!("round {} is not complete", round_id)));
+        }
+        if updated_at > Uint::from(u64::MAX) {
+            return Err(BermudaError::Overflow(format!("update time {}", updated_at)));
+        }
+        let updated_at = updated_at.as_u64();
+        let age = self.blockchain_reader.block_timestamp().await?.saturating_sub(updated_at);
+        if let Some(max_age) = self.limits.max_age {
+            if age > max_age {
+                return Err(stale(format!("last updated {}s ago, more than {}s", age, max_age)));
+            }
+        }
+
+        let decimals = decimals.as_u32() as u8;
+        let answer = match answer.bit(255) {
+            // two's complement
+            true => -Amount::new((!answer).overflowing_add(Uint::one()).0, decimals).to_f64(),
+            false => Amount::new(answer, decimals).to_f64(),
+        };
+        // a price is never zero nor negative, whatever the limits
+        let (min, max) = (
+            self.limits.min_answer.unwrap_or(0.0),
+            self.limits.max_answer.unwrap_or(f64::INFINITY),
+        );
+        if answer <= 0.0 || answer < min || answer > max {
+            return Err(BermudaError::OracleOutOfRange {
+                feed: self.chainlink_address,
+                answer,
+                min,
+                max,
+            });
+        }
+
+        Ok(PriceReading {
+            answer,
+            round_id,
+            updated_at,
+            age,
+        })
+    }
+
+    /// The latest answer of the feed, scaled by its decimals.
+    pub async fn get_answer(&self) -> Result<f64> {
+        Ok(self.get_reading().await?.answer)
+    }
+
+    /// The price of 1 eth in $, the feed being an ETH / USD one.
+    pub async fn get_eth_price(&self) -> Result<PriceReading> {
+        self.get_reading().await
+    }
+
+    /// The answers of the feed between the `from` and `to` unix timestamps, oldest first.
+    ///
+    /// Rounds are walked backwards from the last one answered at `to`, found by binary search,
+    /// through every aggregator the proxy used (its phases). Rounds without data are skipped,
+    /// up to `MAX_ROUND_GAP` in a row. Rounds already in `cache` are not read again.
+    pub async fn get_history(&self, from: u64, to: u64, cache: &mut RoundCache) -> Result<Vec<PricePoint>> {
+        let decimals = uint(&self.call("decimals", &[]).await?, 0)?;
+        if decimals > Uint::from(77) {
+            return Err(BermudaError::Overflow(format!("{} decimals", decimals)));
+        }
+        let decimals = decimals.as_u32() as u8;
+        let latest_round = uint(&self.call("latestRoundData", &[]).await?, 0)?;
+        let latest_phase = uint(&self.call("phaseId", &[]).await?, 0)?.low_u64();
+
+        let mut points = Vec::new();
+        'phases: for phase in (1..=latest_phase).rev() {
+            let aggregator = address(&self.call("phaseAggregators", &[Token::Uint(Uint::from(phase))]).await?, 0)?;
+            if aggregator.is_zero() {
+                continue;
+            }
+            let last_round = match phase == latest_phase {
+                true => latest_round.low_u64(),
+                false => {
+                    let tokens = self
+                        .blockchain_reader
+                        .call_function(&self.chainlink_contract, &aggregator, "latestRound", &[])
+                        .await?;
+                    uint(&tokens, 0)?.low_u64()
+                }
+            };
+            let mut round = self.find_round(phase, last_round, to, decimals, cache).await?;
+            let mut gap = 0;
+            while round > 0 {
+                let first = round.saturating_sub(ROUNDS_PER_BATCH - 1).max(1);
+                let ids: Vec<Uint> = (first..=round).rev().map(|round| round_id(phase, round)).collect();
+                self.fill_cache(&ids, decimals, cache).await?;
+                for id in ids.iter() {
+                    let point = match cache.get(id) {
+                        Some(point) => *point,
+                        None => {
+                            gap += 1;
+                            match gap > MAX_ROUND_GAP {
+                                // older rounds of this phase are not available
+                                true => continue 'phases,
+                                false => continue,
+                            }
+                        }
+                    };
+                    gap = 0;
+                    if point.timestamp < from {
+                        break 'phases;
+                    }
+                    if point.timestamp <= to {
+                        points.push(point);
+                    }
+                }
+                round = first - 1;
+            }
+        }
+        cache.save()?;
+        points.reverse();
+        Ok(points)
+    }
+
+    /// The last round of `phase` up to `last_round` answered at `to` or before, the first
+    /// available round of the phase when they are all answered after `to`.
+    async fn find_round(&self, phase: u64, last_round: u64, to: u64, decimals: u8, cache: &mut RoundCache) -> Result<u64> {
+        if last_round == 0 {
+            return Ok(0);
+        }
+        let (mut low, mut high) = (1, last_round);
+        while low < high {
+            let middle = low + (high - low).div_ceil(2);
+            match self.round_at_or_before(phase, middle, low, decimals, cache).await? {
+                // the rounds after the one found and up to `middle` have no data
+                Some((_, point)) if point.timestamp <= to => low = middle,
+                Some((round, _)) => high = round - 1,
+                // too many rounds without data, the older ones are not available
+                None => low = middle,
+            }
+        }
+        Ok(low)
+    }
+
+    /// The nearest round with data at `round` or before, down to `low` and at most
+    /// `MAX_ROUND_GAP` rounds away.
+    async fn round_at_or_before(
+        &self,
+        phase: u64,
+        round: u64,
+        low: u64,
+        decimals: u8,
+        cache: &mut RoundCache,
+    ) -> Result<Option<(u64, PricePoint)>> {
+        let id = round_id(phase, round);
+        self.fill_cache(&[id], decimals, cache).await?;
+        if let Some(point) = cache.get(&id) {
+            return Ok(Some((round, *point)));
+        }
+        let rounds: Vec<u64> = (round.saturating_sub(MAX_ROUND_GAP).max(low)..round).rev().collect();
+        let ids: Vec<Uint> = rounds.iter().map(|round| round_id(phase, *round)).collect();
+        self.fill_cache(&ids, decimals, cache).await?;
+        Ok(rounds
+            .into_iter()
+            .zip(ids.iter())
+            .find_map(|(round, id)| cache.get(id).map(|point| (round, *point))))
+    }
+
+    async fn call(&self, name: &str, params: &[Token]) -> Result<Vec<Token>> {
+        self.blockchain_reader
+            .call_function(&self.chainlink_contract, &self.chainlink_address, name, params)
+            .await
+    }
+
+    /// Read the answered rounds of `ids` that are not cached yet.
+    async fn fill_cache(&self, ids: &[Uint], decimals: u8, cache: &mut RoundCache) -> Result<()> {
+        let missing: Vec<&Uint> = ids.iter().filter(|id| cache.get(id).is_none()).collect();
+        let mut batch = Batch::new();
+        for id in missing.iter() {
+            batch.add(&self.chainlink_contract, &self.chainlink_address, "getRoundData", &[Token::Uint(**id)])?;
+        }
+        for (id, result) in missing.into_iter().zip(self.blockchain_reader.call_batch(&batch).await?) {
+            let round = match result {
+                Ok(round) => round,
+                Err(BermudaError::Revert { .. }) => continue,
+                Err(error) => return Err(error),
+            };
+            let answer = int(&round, 1)?;
+            let updated_at = uint(&round, 3)?;
+            if answer.bit(255) || updated_at.is_zero() || uint(&round, 4)? < *id {
+                continue;
+            }
+            cache.insert(PricePoint {
+                round_id: *id,
+                timestamp: updated_at.low_u64(),
+                price: Amount::new(answer, decimals).to_f64(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The id of `round` of the aggregator of `phase`, as the proxy numbers it.
+fn round_id(phase: u64, round: u64) -> Uint {
+    (Uint::from(phase) << 64) | Uint::from(round)
+}
+
+/// Chainlink feeds keyed by the pair they quote, such as `ETH/USD` for the price of 1 eth in $.
+///
+/// Rates are read directly, inverted from the opposite feed, or crossed through a common
+/// currency: ETH/EUR is ETH/USD divided by EUR/USD. Every feed read is checked against the
+/// limits of the registry, `OracleLimits::daily` unless set.
+#[derive(Debug, Clone)]
+pub struct FeedRegistry {
+    feeds: HashMap<(String, String), Address>,
+    limits: OracleLimits,
+}
+
+impl Default for FeedRegistry {
+    fn default() -> Self {
+        Self {
+            feeds: HashMap::new(),
+            limits: OracleLimits::daily(),
+        }
+    }
+}
+
+/// One feed read to compose a rate, inverted when it quotes the opposite pair.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeedLeg {
+    pub feed: Address,
+    pub inverse: bool,
+}
+
+impl FeedRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The mainnet feeds of ETH/USD, EUR/USD, BTC/USD, stETH/ETH and USDC/USD.
+    pub fn mainnet() -> Self {
+        let mut registry = Self::new();
+        for (pair, address) in [
+            ("ETH/USD", CHAINLINK_ADDRESS),
+            ("EUR/USD", EUR_USD_ADDRESS),
+            ("BTC/USD", "F4030086522a5bEEa4988F8cA5B36dbC97BeE88c"),
+            ("STETH/ETH", "86392dC19c0b719886221c78AB11eb8Cf5c52812"),
+            ("USDC/USD", "8fFfFfd4AfB6115b954Bd326cbe7B4BA576818f6"),
+        ] {
+            registry.add(pair, address.parse().unwrap()).unwrap();
+        }
+        registry
+    }
+
+    /// Check every feed read against `limits`.
+    pub fn with_limits(self, limits: OracleLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Register the feed quoting `pair`, such as `EUR/USD`.
+    pub fn add(&mut self, pair: &str, feed: Address) -> Result<()> {
+        self.feeds.insert(parse_pair(pair)?, feed);
+        Ok(())
+    }
+
+    fn leg(&self, base: &str, quote: &str) -> Option<FeedLeg> {
+        let direct = self.feeds.get(&(base.to_string(), quote.to_string()));
+        let inverse = self.feeds.get(&(quote.to_string(), base.to_string()));
+        match (direct, inverse) {
+            (Some(&feed), _) => Some(FeedLeg { feed, inverse: false }),
+            (None, Some(&feed)) => Some(FeedLeg { feed, inverse: true }),
+            (None, None) => None,
+        }
+    }
+
+    /// The feeds to read for `pair`: one for a direct or inverse quote, two for a cross rate.
+    pub fn route(&self, pair: &str) -> Result<Vec<FeedLeg>> {
+        let (base, quote) = parse_pair(pair)?;
+        if let Some(leg) = self.leg(&base, &quote) {
+            return Ok(vec![leg]);
+        }
+        let mut currencies: Vec<&String> = self.feeds.keys().flat_map(|(a, b)| vec![a, b]).collect();
+        currencies.sort();
+        currencies
+            .into_iter()
+            .find_map(|via| Some(vec![self.leg(&base, via)?, self.leg(via, &quote)?]))
+            .ok_or_else(|| BermudaError::Input(format!("no chainlink feed to price {}", pair)))
+    }
+
+    /// The price of 1 base in quote, such as 1850.0 for `ETH/EUR`.
+    pub async fn get_rate<R: BlockchainReader>(&self, blockchain_reader: &R, pair: &str) -> Result<f64> {
+        let mut rate = 1.0;
+        for leg in self.route(pair)? {
+            let answer = Chainlink::at(blockchain_reader, leg.feed)?
+                .with_limits(self.limits)
+                .get_answer()
+                .await?;
+            rate *= match leg.inverse {
+                true => 1.0 / answer,
+                false => answer,
+            };
+        }
+        Ok(rate)
+    }
+}
+
+/// Split `BASE/QUOTE`, ignoring the case of the symbols.
+fn parse_pair(pair: &str) -> Result<(String, String)> {
+    match pair.find('/') {
+        Some(slash) if slash > 0 && slash + 1 < pair.len() => Ok((
+            pair[..slash].to_uppercase(),
+            pair[slash + 1..].to_uppercase(),
+        )),
+        _ => Err(BermudaError::Input(format!("invalid pair {}, expected BASE/QUOTE", pair))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+
+    const UPDATED_AT: u64 = 1_700_000_000;
+
+    fn mock_feed(answer: u64, round_id: u64, answered_in_round: u64) -> MockBlockchainReader {
+        let mut reader = MockBlockchainReader::new();
+        add_feed(&mut reader, &CHAINLINK_ADDRESS.parse().unwrap(), answer, round_id, answered_in_round);
+        reader
+    }
+
+    fn add_feed(reader: &mut MockBlockchainReader, address: &Address, answer: u64, round_id: u64, answered_in_round: u64) {
+        let contract = Contract::load(&include_bytes!("abi/chainlink.abi")[..]).unwrap();
+        let round = [
+            Token::Uint(Uint::from(round_id)),
+            Token::Int(Uint::from(answer)),
+            Token::Uint(Uint::from(UPDATED_AT)),
+            Token::Uint(Uint::from(UPDATED_AT)),
+            Token::Uint(Uint::from(answered_in_round)),
+        ];
+        reader.mock_call(&contract, address, "latestRoundData", &[], &round).unwrap();
+        reader
+            .mock_call(&contract, address, "decimals", &[], &[Token::Uint(Uint::from(8))])
+            .unwrap();
+        reader.mock_timestamp(UPDATED_AT + 600);
+    }
+
+    #[tokio::test]
+    async fn get_reading_checks_the_limits() {
+        let reader = mock_feed(2000_0000_0000, 42, 42);
+        let reading = Chainlink::new(&reader)
+            .unwrap()
+            .with_limits(OracleLimits::eth_usd())
+            .get_reading()
+            .await
+            .unwrap();
+        assert_eq!(reading.answer, 2000.0);
+        assert_eq!(reading.round_id, Uint::from(42));
+        assert_eq!(reading.age, 600);
+
+        let stale = OracleLimits { max_age: Some(300), ..OracleLimits::default() };
+        let error = Chainlink::new(&reader).unwrap().with_limits(stale).get_reading().await;
+        assert!(matches!(error, Err(BermudaError::StaleOracle { .. })));
+
+        let band = OracleLimits { min_answer: Some(100.0), max_answer: Some(1000.0), ..OracleLimits::default() };
+        let error = Chainlink::new(&reader).unwrap().with_limits(band).get_reading().await;
+        assert!(matches!(error, Err(BermudaError::OracleOutOfRange { .. })));
+    }
+
+    #[tokio::test]
+    async fn zero_and_negative_answers_are_out_of_range() {
+        let reader = mock_feed(0, 42, 42);
+        let error = Chainlink::new(&reader).unwrap().with_limits(OracleLimits::eth_usd()).get_reading().await;
+        assert!(matches!(error, Err(BermudaError::OracleOutOfRange { answer, .. }) if answer == 0.0));
+
+        let mut reader = MockBlockchainReader::new();
+        add_feed(&mut reader, &CHAINLINK_ADDRESS.parse().unwrap(), 0, 42, 42);
+        let contract = Contract::load(&include_bytes!("abi/chainlink.abi")[..]).unwrap();
+        let minus_one = !Uint::exp10(8) + Uint::one();
+        let round = [
+            Token::Uint(Uint::from(42)),
+            Token::Int(minus_one),
+            Token::Uint(Uint::from(UPDATED_AT)),
+            Token::Uint(Uint::from(UPDATED_AT)),
+            Token::Uint(Uint::from(42)),
+        ];
+        reader.mock_call(&contract, &CHAINLINK_ADDRESS.parse().unwrap(), "latestRoundData", &[], &round).unwrap();
+        let error = Chainlink::new(&reader).unwrap().get_reading().await;
+        assert!(matches!(error, Err(BermudaError::OracleOutOfRange { answer, .. }) if answer == -1.0));
+    }
+
+    #[tokio::test]
+    async fn incomplete_round_is_stale() {
+        let reader = mock_feed(2000_0000_0000, 42, 41);
+        let error = Chainlink::new(&reader).unwrap().get_reading().await;
+        assert!(matches!(error, Err(BermudaError::StaleOracle { .. })));
+    }
+
+    #[test]
+    fn registry_routes() {
+        let registry = FeedRegistry::mainnet();
+        let eth_usd: Address = CHAINLINK_ADDRESS.parse().unwrap();
+        assert_eq!(registry.route("eth/usd").unwrap(), vec![FeedLeg { feed: eth_usd, inverse: false }]);
+        assert_eq!(registry.route("USD/ETH").unwrap(), vec![FeedLeg { feed: eth_usd, inverse: true }]);
+        assert_eq!(registry.route("ETH/EUR").unwrap().len(), 2);
+        assert_eq!(registry.route("STETH/USD").unwrap().len(), 2);
+        assert!(registry.route("ETH/JPY").is_err());
+        assert!(registry.route("ETH").is_err());
+    }
+
+    #[tokio::test]
+    async fn registry_crosses_rates() {
+        let mut reader = mock_feed(2000_0000_0000, 1, 1);
+        let eur_usd: Address = "b49f677943BC038e9857d61E7d053CaA2C1734C1".parse().unwrap();
+        add_feed(&mut reader, &eur_usd, 1_2500_0000, 1, 1);
+        let registry = FeedRegistry::mainnet();
+        assert_eq!(registry.get_rate(&reader, "EUR/USD").await.unwrap(), 1.25);
+        assert_eq!(registry.get_rate(&reader, "USD/EUR").await.unwrap(), 0.8);
+        assert_eq!(registry.get_rate(&reader, "ETH/EUR").await.unwrap(), 1600.0);
+
+        let stale = registry.with_limits(OracleLimits { max_age: Some(300), ..OracleLimits::default() });
+        let error = stale.get_rate(&reader, "ETH/EUR").await;
+        assert!(matches!(error, Err(BermudaError::StaleOracle { .. })));
+    }
+
+    #[tokio::test]
+    async fn history_walks_back_across_phases() {
+        let contract = Contract::load(&include_bytes!("abi/chainlink.abi")[..]).unwrap();
+        let proxy: Address = CHAINLINK_ADDRESS.parse().unwrap();
+        let old_aggregator: Address = "0000000000000000000000000000000000000a01".parse().unwrap();
+        let aggregator: Address = "0000000000000000000000000000000000000a02".parse().unwrap();
+        // (phase, round, timestamp, price)
+        let rounds = [(2, 3, 500, 30), (2, 2, 400, 25), (2, 1, 300, 20), (1, 2, 200, 15), (1, 1, 100, 10)];
+
+        let mut reader = mock_feed(30_0000_0000, 0, 0);
+        let round_data = |id: Uint, timestamp: u64, price: u64| {
+            vec![
+                Token::Uint(id),
+                Token::Int(Uint::from(price) * Uint::exp10(8)),
+                Token::Uint(Uint::from(timestamp)),
+                Token::Uint(Uint::from(timestamp)),
+                Token::Uint(id),
+            ]
+        };
+        let mut mock = |address: &Address, name: &str, params: &[Token], outputs: &[Token]| {
+            reader.mock_call(&contract, address, name, params, outputs).unwrap();
+        };
+        mock(&proxy, "latestRoundData", &[], &round_data(round_id(2, 3), 500, 30));
+        mock(&proxy, "phaseId", &[], &[Token::Uint(Uint::from(2))]);
+        mock(&proxy, "phaseAggregators", &[Token::Uint(Uint::from(2))], &[Token::Address(aggregator)]);
+        mock(&proxy, "phaseAggregators", &[Token::Uint(Uint::from(1))], &[Token::Address(old_aggregator)]);
+        mock(&old_aggregator, "latestRound", &[], &[Token::Uint(Uint::from(2))]);
+        for (phase, round, timestamp, price) in rounds.iter() {
+            let id = round_id(*phase, *round);
+            mock(&proxy, "getRoundData", &[Token::Uint(id)], &round_data(id, *timestamp, *price));
+        }
+
+        let chainlink = Chainlink::new(&reader).unwrap();
+        let mut cache = RoundCache::in_memory();
+        let history = chainlink.get_history(150, 450, &mut cache).await.unwrap();
+        let prices: Vec<(u64, f64)> = history.iter().map(|point| (point.timestamp, point.price)).collect();
+        assert_eq!(prices, vec![(200, 15.0), (300, 20.0), (400, 25.0)]);
+
+        // the second walk only reads the cache
+        let calls = reader.eth_call_count();
+        let again = chainlink.get_history(150, 450, &mut cache).await.unwrap();
+        assert_eq!(again, history);
+        assert!(reader.eth_call_count() - calls <= 6);
+    }
+
+    #[tokio::test]
+    async fn history_skips_missing_rounds_from_the_round_at_to() {
+        let contract = Contract::load(&include_bytes!("abi/chainlink.abi")[..]).unwrap();
+        let proxy: Address = CHAINLINK_ADDRESS.parse().unwrap();
+        let aggregator: Address = "0000000000000000000000000000000000000a02".parse().unwrap();
+        let round_data = |round: u64| {
+            let id = round_id(1, round);
+            vec![
+                Token::Uint(id),
+                Token::Int(Uint::from(round) * Uint::exp10(8)),
+                Token::Uint(Uint::from(round * 10)),
+                Token::Uint(Uint::from(round * 10)),
+                Token::Uint(id),
+            ]
+        };
 
-        let price = price.unwrap();
+        let mut reader = mock_feed(1000_0000_0000, 0, 0);
+        reader.mock_call(&contract, &proxy, "latestRoundData", &[], &round_data(1000)).unwrap();
+        reader.mock_call(&contract, &proxy, "phaseId", &[], &[Token::Uint(Uint::one())]).unwrap();
+        let phase_aggregator = [Token::Uint(Uint::one())];
+        reader.mock_call(&contract, &proxy, "phaseAggregators", &phase_aggregator, &[Token::Address(aggregator)]).unwrap();
+        // rounds 500 to 505 have no data, and nothing is available before round 100
+        let get_round_data = contract.function("getRoundData").unwrap();
+        for round in 1..=1000 {
+            let params = [Token::Uint(round_id(1, round))];
+            match round {
+                100..=499 | 506..=1000 => reader.mock_call(&contract, &proxy, "getRoundData", &params, &round_data(round)).unwrap(),
+                _ => reader.mock_revert(&proxy, get_round_data.encode_input(&params).unwrap(), Vec::new()),
+            }
+        }
 
-        let eth_price = 1.0 / (price.as_u128() as f64 / Uint::exp10(18).as_u128() as f64);
+        let chainlink = Chainlink::new(&reader).unwrap();
+        let history = chainlink.get_history(4_900, 5_200, &mut RoundCache::in_memory()).await.unwrap();
+        let rounds: Vec<u64> = history.iter().map(|point| point.timestamp / 10).collect();
+        assert_eq!(rounds, (490..500).chain(506..=520).collect::<Vec<_>>());
+        // the walk starts at round 520, not at the latest one
+        assert!(reader.eth_call_count() < 200);
 
-        Ok(eth_price)
+        let history = chainlink.get_history(0, 1_200, &mut RoundCache::in_memory()).await.unwrap();
+        let rounds: Vec<u64> = history.iter().map(|point| point.timestamp / 10).collect();
+        assert_eq!(rounds, (100..=120).collect::<Vec<_>>());
     }
 }
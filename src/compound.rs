@@ -1,29 +1,114 @@
-use super::blockchain::HttpBlockchainReader;
+use super::blockchain::BlockchainReader;
+use super::multicall::Batch;
 use super::Loan;
-use ethabi::Uint;
+use super::amount::Amount;
+use super::decode::{address, boolean, parse_address, tuple, uint};
+use super::error::Result;
 use ethabi::{Address, Contract, Token};
-use std::error::Error;
+use serde::Serialize;
 
-const COMPOUND_ADDRESS: &str = "c3d688B66703497DAA19211EEdff47f25384cdc3";
-const WETH_ADDRESS: &str = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
-pub struct Compound<'a> {
-    blockchain_reader: &'a HttpBlockchainReader,
+pub(crate) const COMPOUND_ADDRESS: &str = "c3d688B66703497DAA19211EEdff47f25384cdc3";
+pub(crate) const WETH_ADDRESS: &str = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+pub(crate) const USDC_DECIMALS: u8 = 6;
+// Comet prices have 8 decimals and its collateral factors 18
+const PRICE_DECIMALS: u8 = 8;
+const FACTOR_DECIMALS: u8 = 18;
+
+/// The long leg as Comet sees it, priced with Comet's own feeds. Factors are fractions
+/// of 1 (0.83 for a 83% borrow collateral factor), prices are in $.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CometAccount {
+    pub collateral: Amount,
+    pub debt: Amount,
+    pub collateral_price: f64,
+    pub base_price: f64,
+    pub borrow_collateral_factor: f64,
+    pub liquidate_collateral_factor: f64,
+    pub liquidation_factor: f64,
+    pub is_liquidatable: bool,
+    pub is_borrow_collateralized: bool,
+}
+
+impl CometAccount {
+    pub fn loan(&self) -> Loan {
+        Loan {
+            collateral: self.collateral.to_f64(),
+            debt: self.debt_usd() / self.collateral_price,
+        }
+    }
+
+    /// The loan with the USDC debt converted at `eth_price`, as `Compound::get_loan` reads it.
+    pub fn loan_at(&self, eth_price: f64) -> Loan {
+        Loan {
+            collateral: self.collateral.to_f64(),
+            debt: self.debt.to_f64() / eth_price,
+        }
+    }
+
+    fn debt_usd(&self) -> f64 {
+        self.debt.to_f64() * self.base_price
+    }
+
+    /// The liquidation value of the collateral over the debt, None when there is no debt.
+    pub fn health_factor(&self) -> Option<f64> {
+        match self.debt.is_zero() {
+            true => None,
+            false => Some(
+                self.collateral.to_f64() * self.collateral_price * self.liquidate_collateral_factor
+                    / self.debt_usd(),
+            ),
+        }
+    }
+
+    /// The eth price at which the account gets absorbed, for the long leg: eth collateral
+    /// and stablecoin debt, so the health factor falls with the eth price.
+    pub fn liquidation_price(&self) -> Option<f64> {
+        match self.debt.is_zero() || self.collateral.is_zero() {
+            true => None,
+            false => Some(
+                self.debt_usd() / (self.collateral.to_f64() * self.liquidate_collateral_factor),
+            ),
+        }
+    }
+
+    /// How much the eth price can fall before absorption, -0.25 for -25%.
+    pub fn distance_to_liquidation(&self) -> Option<f64> {
+        self.liquidation_price()
+            .map(|liquidation_price| liquidation_price / self.collateral_price - 1.0)
+    }
+
+    /// What can still be borrowed, in $. Negative when the account is above its borrow limit.
+    pub fn borrow_capacity_left(&self) -> f64 {
+        self.collateral.to_f64() * self.collateral_price * self.borrow_collateral_factor
+            - self.debt_usd()
+    }
+}
+
+pub struct Compound<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
     compound_address: Address,
     compound_contract: Contract,
+    weth_address: Address,
 }
-impl<'a> Compound<'a> {
-    pub async fn new(blockchain_reader: &'a HttpBlockchainReader ) -> Result<Compound<'a>, Box<dyn Error>> {
-        let compound_address: Address = COMPOUND_ADDRESS.parse()?;
+impl<'a, R: BlockchainReader> Compound<'a, R> {
+    pub async fn new(blockchain_reader: &'a R) -> Result<Compound<'a, R>> {
+        Self::at(blockchain_reader, parse_address(COMPOUND_ADDRESS)?, parse_address(WETH_ADDRESS)?)
+    }
+
+    /// The Comet market deployed at `compound_address`, with WETH at `weth_address` as collateral.
+    pub fn at(blockchain_reader: &'a R, compound_address: Address, weth_address: Address) -> Result<Compound<'a, R>> {
         let compound_abi: &[u8] = include_bytes!("abi/cUSDCv3.abi");
         let compound_contract: Contract = Contract::load(compound_abi)?;
         Ok(Self {
             blockchain_reader,
             compound_address,
             compound_contract,
+            weth_address,
         })
     }
 
-    pub async fn get_eth_col(&self, owner_address: &str) -> Result<f64, Box<dyn Error>> {
+    /// The WETH collateral of the owner.
+    pub async fn get_eth_col(&self, owner_address: &str) -> Result<Amount> {
 
         let tokens = self
             .blockchain_reader
@@ -31,20 +116,17 @@ impl<'a> Compound<'a> {
                 &self.compound_contract,
                 &self.compound_address,
                 "userCollateral",
-                &[Token::Address(owner_address.parse()?), Token::Address(WETH_ADDRESS.parse()?)],
+                &[Token::Address(parse_address(owner_address)?), Token::Address(self.weth_address)],
                 )
             .await?;
 
-        let col = tokens[0].clone().into_uint();
-        let col = col.unwrap();
-
-        let eth_value =
-            (col.as_u128() as f64) / Uint::exp10(18).as_u128() as f64;
+        let col = uint(&tokens, 0)?;
 
-        Ok(eth_value)
+        Ok(Amount::from_wei(col))
     }
 
-    pub async fn get_eth_debt(&self, owner_address: &str, eth_price: f64) -> Result<f64, Box<dyn Error>> {
+    /// The USDC debt of the owner.
+    pub async fn get_debt(&self, owner_address: &str) -> Result<Amount> {
 
         let tokens = self
             .blockchain_reader
@@ -52,31 +134,215 @@ impl<'a> Compound<'a> {
                 &self.compound_contract,
                 &self.compound_address,
                 "borrowBalanceOf",
-                &[Token::Address(owner_address.parse()?)],
+                &[Token::Address(parse_address(owner_address)?)],
                 )
             .await?;
 
-        let debt = tokens[0].clone().into_uint();
-        let debt = debt.unwrap();
+        let debt = uint(&tokens, 0)?;
+
+        Ok(Amount::new(debt, USDC_DECIMALS))
+    }
+
+    pub async fn get_eth_debt(&self, owner_address: &str, eth_price: f64) -> Result<f64> {
+        let debt = self.get_debt(owner_address).await?;
+        Ok(debt.to_f64() / eth_price)
+    }
+
+    pub async fn get_loan(&self, owner_address: &str, eth_price: f64) -> Result<Loan> {
+        let owner = parse_address(owner_address)?;
+        let mut batch = Batch::new();
+        let col_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "userCollateral",
+            &[Token::Address(owner), Token::Address(self.weth_address)],
+        )?;
+        let debt_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "borrowBalanceOf",
+            &[Token::Address(owner)],
+        )?;
+        let mut results = self.blockchain_reader.call_batch(&batch).await?;
+        let debt = Amount::new(uint(&results.remove(debt_call)?, 0)?, USDC_DECIMALS);
+        let col = Amount::from_wei(uint(&results.remove(col_call)?, 0)?);
+
+        Ok(Loan{collateral: col.to_f64(), debt: debt.to_f64() / eth_price})
+    }
+
+    /// The position of the owner with the collateral factors and prices of the market.
+    pub async fn get_account(&self, owner_address: &str) -> Result<CometAccount> {
+        let owner = parse_address(owner_address)?;
+        let mut batch = Batch::new();
+        let col_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "userCollateral",
+            &[Token::Address(owner), Token::Address(self.weth_address)],
+        )?;
+        let debt_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "borrowBalanceOf",
+            &[Token::Address(owner)],
+        )?;
+        let asset_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "getAssetInfoByAddress",
+            &[Token::Address(self.weth_address)],
+        )?;
+        let base_feed_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "baseTokenPriceFeed",
+            &[],
+        )?;
+        let liquidatable_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "isLiquidatable",
+            &[Token::Address(owner)],
+        )?;
+        let collateralized_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "isBorrowCollateralized",
+            &[Token::Address(owner)],
+        )?;
+        let results = self.blockchain_reader.call_batch(&batch).await?;
+        let result = |index: usize| results[index].clone();
+        let asset_info = tuple(&result(asset_call)?, 0)?;
+        let factor = |index: usize| -> Result<f64> {
+            Ok(Amount::new(uint(&asset_info, index)?, FACTOR_DECIMALS).to_f64())
+        };
 
-        let eth_value =
-            (debt.as_u128() as f64) / (Uint::exp10(6).as_u128() as f64 * eth_price);
+        let mut batch = Batch::new();
+        let collateral_price_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "getPrice",
+            &[Token::Address(address(&asset_info, 2)?)],
+        )?;
+        let base_price_call = batch.add(
+            &self.compound_contract,
+            &self.compound_address,
+            "getPrice",
+            &[Token::Address(address(&result(base_feed_call)?, 0)?)],
+        )?;
+        let prices = self.blockchain_reader.call_batch(&batch).await?;
+        let price = |index: usize| -> Result<f64> {
+            let tokens = prices[index].clone()?;
+            Ok(Amount::new(uint(&tokens, 0)?, PRICE_DECIMALS).to_f64())
+        };
 
-        Ok(eth_value)
+        Ok(CometAccount {
+            collateral: Amount::from_wei(uint(&result(col_call)?, 0)?),
+            debt: Amount::new(uint(&result(debt_call)?, 0)?, USDC_DECIMALS),
+            collateral_price: price(collateral_price_call)?,
+            base_price: price(base_price_call)?,
+            borrow_collateral_factor: factor(4)?,
+            liquidate_collateral_factor: factor(5)?,
+            liquidation_factor: factor(6)?,
+            is_liquidatable: boolean(&result(liquidatable_call)?, 0)?,
+            is_borrow_collateralized: boolean(&result(collateralized_call)?, 0)?,
+        })
     }
 
-    pub async fn get_loan(&self, owner_address: &str, eth_price: f64) -> Result<Loan, Box<dyn Error>> {
-        let col =  self.get_eth_col(owner_address).await?;
-        let debt = self.get_eth_debt(owner_address, eth_price).await?;
-        Ok(Loan{collateral:col, debt})
+    pub async fn get_eth_value(&self, owner_address: &str, eth_price: f64) -> Result<f64> {
+        let loan = self.get_loan(owner_address, eth_price).await?;
+        Ok(loan.collateral - loan.debt)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+    use ethabi::Uint;
 
-    pub async fn get_eth_value(&self, owner_address: &str, eth_price: f64) -> Result<f64, Box<dyn Error>> {
-        let col = self.get_eth_col(owner_address).await?;
-        let debt = self.get_eth_debt(owner_address, eth_price).await?;
+    #[tokio::test]
+    async fn get_loan_falls_back_without_multicall() {
+        let owner: Address = "0000000000000000000000000000000000000001".parse().unwrap();
+        let contract = Contract::load(&include_bytes!("abi/cUSDCv3.abi")[..]).unwrap();
+        let address: Address = COMPOUND_ADDRESS.parse().unwrap();
+        let mut reader = MockBlockchainReader::new();
+        reader
+            .mock_call(
+                &contract,
+                &address,
+                "userCollateral",
+                &[Token::Address(owner), Token::Address(WETH_ADDRESS.parse().unwrap())],
+                &[Token::Uint(Uint::exp10(18) * 12), Token::Uint(Uint::zero())],
+            )
+            .unwrap();
+        reader
+            .mock_call(
+                &contract,
+                &address,
+                "borrowBalanceOf",
+                &[Token::Address(owner)],
+                &[Token::Uint(Uint::exp10(6) * 8000)],
+            )
+            .unwrap();
+        let compound = Compound::new(&reader).await.unwrap();
+
+        let loan = compound.get_loan("0000000000000000000000000000000000000001", 2000.0).await.unwrap();
+        assert_eq!(loan.collateral, 12.0);
+        assert_eq!(loan.debt, 4.0);
+        assert_eq!(compound.get_eth_value("0000000000000000000000000000000000000001", 2000.0).await.unwrap(), 8.0);
+    }
 
-        let eth_value = col - debt;
+    #[tokio::test]
+    async fn get_account_uses_comet_prices() {
+        let owner: Address = "0000000000000000000000000000000000000001".parse().unwrap();
+        let contract = Contract::load(&include_bytes!("abi/cUSDCv3.abi")[..]).unwrap();
+        let address: Address = COMPOUND_ADDRESS.parse().unwrap();
+        let weth: Address = WETH_ADDRESS.parse().unwrap();
+        let eth_feed: Address = "0000000000000000000000000000000000000e7f".parse().unwrap();
+        let usdc_feed: Address = "00000000000000000000000000000000000005dc".parse().unwrap();
+        let factor = |percent: u64| Token::Uint(Uint::exp10(16) * percent);
+        let mut reader = MockBlockchainReader::new();
+        let mut mock = |name: &str, params: &[Token], outputs: &[Token]| {
+            reader.mock_call(&contract, &address, name, params, outputs).unwrap();
+        };
+        mock(
+            "userCollateral",
+            &[Token::Address(owner), Token::Address(weth)],
+            &[Token::Uint(Uint::exp10(18) * 10), Token::Uint(Uint::zero())],
+        );
+        mock("borrowBalanceOf", &[Token::Address(owner)], &[Token::Uint(Uint::exp10(6) * 12000)]);
+        mock(
+            "getAssetInfoByAddress",
+            &[Token::Address(weth)],
+            &[Token::Tuple(vec![
+                Token::Uint(Uint::from(2)),
+                Token::Address(weth),
+                Token::Address(eth_feed),
+                Token::Uint(Uint::exp10(18)),
+                factor(80),
+                factor(75),
+                factor(93),
+                Token::Uint(Uint::exp10(24)),
+            ])],
+        );
+        mock("baseTokenPriceFeed", &[], &[Token::Address(usdc_feed)]);
+        mock("isLiquidatable", &[Token::Address(owner)], &[Token::Bool(false)]);
+        mock("isBorrowCollateralized", &[Token::Address(owner)], &[Token::Bool(true)]);
+        mock("getPrice", &[Token::Address(eth_feed)], &[Token::Uint(Uint::exp10(8) * 2000)]);
+        mock("getPrice", &[Token::Address(usdc_feed)], &[Token::Uint(Uint::exp10(8))]);
+        let compound = Compound::new(&reader).await.unwrap();
 
-        Ok(eth_value)
+        let account = compound.get_account("0000000000000000000000000000000000000001").await.unwrap();
+        assert_eq!(account.collateral_price, 2000.0);
+        assert_eq!(account.liquidate_collateral_factor, 0.75);
+        assert_eq!(account.health_factor(), Some(1.25));
+        assert_eq!(account.liquidation_price(), Some(1600.0));
+        assert_eq!(account.distance_to_liquidation().map(|d| (d * 100.0).round()), Some(-20.0));
+        assert_eq!(account.borrow_capacity_left(), 4000.0);
+        // the debt converted at the chainlink price rather than at the price of Comet
+        assert_eq!(account.loan_at(2400.0).debt, 5.0);
+        assert_eq!(account.loan().debt, 6.0);
+        assert!(!account.is_liquidatable);
     }
 }
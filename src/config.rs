@@ -0,0 +1,355 @@
+use super::aave::AAVE_ADDRESS;
+use super::alert::AlertConfig;
+use super::chainlink::{FeedRegistry, OracleLimits, CHAINLINK_ADDRESS, EUR_USD_ADDRESS};
+use super::compound::{COMPOUND_ADDRESS, WETH_ADDRESS};
+use super::calibration::{exponent_for_band, exponent_for_ratio};
+use super::error::{BermudaError, Result};
+use super::transaction::{RebalanceContracts, SWAP_ROUTER_ADDRESS};
+use super::watchlist::{WatchedToken, USDC_ADDRESS};
+use super::{StrategyParams, KEEP_RATIO, REBALANCE_GAS, SHORT_SHARE, SLIPPAGE, TARGET_RATIO};
+use ethabi::Address;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_NODE: &str = "http://localhost:8545";
+pub const DEFAULT_NETWORK: &str = "mainnet";
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The content of the TOML config file.
+///
+/// ```toml
+/// default_profile = "main"
+///
+/// [networks.goerli]
+/// aave = "0x..."
+/// compound = "0x..."
+/// weth = "0x..."
+/// chainlink = "0x..."
+/// eur_usd = "0x..."
+///
+/// [profiles.main]
+/// node = "http://localhost:8545"
+/// smart_wallet = "0x..."
+/// tokens = ["0x6b175474e89094c44da98b954eedeac495271d0f:stable"]
+///
+/// [profiles.main.strategy]
+/// target_ratio = 1.6
+///
+/// [profiles.main.oracle]
+/// max_age = 3600
+/// min_answer = 100.0
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub networks: HashMap<String, Contracts>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// The contracts of the bermuda on one network. `chainlink` is the ETH / USD feed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Contracts {
+    pub aave: Address,
+    pub compound: Address,
+    pub weth: Address,
+    pub chainlink: Address,
+    /// The stablecoin of both loans and the Uniswap V3 router, only needed by the rebalances.
+    pub usdc: Option<Address>,
+    pub swap_router: Option<Address>,
+    /// The EUR / USD feed, used by `html` when the profile has no `eur_usd` rate.
+    pub eur_usd: Option<Address>,
+}
+
+impl Contracts {
+    pub fn mainnet() -> Self {
+        Self {
+            aave: AAVE_ADDRESS.parse().unwrap(),
+            compound: COMPOUND_ADDRESS.parse().unwrap(),
+            weth: WETH_ADDRESS.parse().unwrap(),
+            chainlink: CHAINLINK_ADDRESS.parse().unwrap(),
+            usdc: Some(USDC_ADDRESS.parse().unwrap()),
+            swap_router: Some(SWAP_ROUTER_ADDRESS.parse().unwrap()),
+            eur_usd: Some(EUR_USD_ADDRESS.parse().unwrap()),
+        }
+    }
+
+    /// The contracts a rebalance through `flash_receiver` talks to, failing when the network
+    /// does not give its stablecoin or swap router.
+    pub fn rebalance(&self, flash_receiver: Address) -> Result<RebalanceContracts> {
+        let missing = |name: &str| BermudaError::Config(format!("the network has no {} address, the rebalances need one", name));
+        Ok(RebalanceContracts {
+            aave: self.aave,
+            compound: self.compound,
+            weth: self.weth,
+            usdc: self.usdc.ok_or_else(|| missing("usdc"))?,
+            swap_router: self.swap_router.ok_or_else(|| missing("swap_router"))?,
+            flash_receiver,
+        })
+    }
+}
+
+/// One portfolio: where to read it and how to manage it.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub node: Option<String>,
+    pub smart_wallet: Option<String>,
+    /// The Aave flash loan receiver used by the rebalance transactions.
+    pub flash_receiver: Option<String>,
+    pub network: Option<String>,
+    /// The price of 1€ in $, read from the EUR/USD feed when not given.
+    pub eur_usd: Option<f64>,
+    /// Watched ERC20 tokens, in the `--token` format.
+    pub tokens: Option<Vec<String>>,
+    #[serde(default)]
+    pub strategy: StrategyConfig,
+    /// Limits of the ETH / USD feed, `OracleLimits::eth_usd` when not configured.
+    pub oracle: Option<OracleLimits>,
+    /// Limits of the feeds pricing the watched tokens and of the EUR / USD feed,
+    /// `OracleLimits::daily` when not configured.
+    pub feed_oracle: Option<OracleLimits>,
+    #[serde(default)]
+    pub costs: CostConfig,
+    /// Rules checked and notified by `watch`.
+    pub alerts: Option<AlertConfig>,
+    /// The SQLite file the snapshots are recorded in, `SnapshotStore::default_path` when not given.
+    pub history: Option<PathBuf>,
+}
+
+/// How the costs of a rebalance are estimated.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostConfig {
+    /// The slippage tolerance of the swap, 0.005 for 0.5%.
+    pub slippage: f64,
+    /// The gas used by the rebalance transaction.
+    pub gas_limit: u64,
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        Self {
+            slippage: SLIPPAGE,
+            gas_limit: REBALANCE_GAS,
+        }
+    }
+}
+
+/// The knobs of `initialize_bermuda` and `predict`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StrategyConfig {
+    pub target_ratio: f64,
+    pub keep_ratio: f64,
+    pub short_share: f64,
+    /// `1 - short_share` when not given.
+    pub long_share: Option<f64>,
+    /// The exponent of `predict`, overriding the calibrated one when given.
+    pub exp_factor: Option<f64>,
+    /// The collateral ratios the loans drift between before a rebalance. The exponent of
+    /// `predict` is calibrated from it when given, from `target_ratio` otherwise.
+    pub rebalance_band: Option<[f64; 2]>,
+}
+
+impl Default for StrategyConfig {
+    fn default() -> Self {
+        Self {
+            target_ratio: TARGET_RATIO,
+            keep_ratio: KEEP_RATIO,
+            short_share: SHORT_SHARE,
+            long_share: None,
+            exp_factor: None,
+            rebalance_band: None,
+        }
+    }
+}
+
+impl StrategyConfig {
+    /// The params of `initialize_bermuda`, not validated yet.
+    pub fn params(&self) -> StrategyParams {
+        StrategyParams {
+            target_ratio: self.target_ratio,
+            keep_ratio: self.keep_ratio,
+            short_share: self.short_share,
+            long_share: self.long_share.unwrap_or(1.0 - self.short_share),
+        }
+    }
+
+    /// The exponent given to `predict`.
+    pub fn exponent(&self) -> Result<f64> {
+        match (self.exp_factor, self.rebalance_band) {
+            (Some(exp_factor), _) => Ok(exp_factor),
+            (None, Some([low, high])) => exponent_for_band(low, high),
+            (None, None) => exponent_for_ratio(self.target_ratio),
+        }
+    }
+}
+
+impl Config {
+    /// `~/.config/bermuda/config.toml`, read when no config file is given.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".config").join("bermuda").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path).map_err(|error| {
+            BermudaError::Config(format!("cannot read {}: {}", path.display(), error))
+        })?;
+        Config::parse(&content)
+            .map_err(|error| BermudaError::Config(format!("{}: {}", path.display(), error)))
+    }
+
+    pub fn parse(content: &str) -> Result<Config> {
+        toml::from_str(content).map_err(|error| BermudaError::Config(error.to_string()))
+    }
+
+    /// The profile called `name`, or the default one. A config without profiles has an
+    /// empty default profile, so that everything can be given on the command line.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let name = name
+            .or(self.default_profile.as_deref())
+            .unwrap_or(DEFAULT_PROFILE);
+        match self.profiles.get(name) {
+            Some(profile) => Ok(profile.clone()),
+            None if name == DEFAULT_PROFILE => Ok(Profile::default()),
+            None => Err(BermudaError::Config(format!("unknown profile {}", name))),
+        }
+    }
+
+    /// The Chainlink feeds of the network of the profile: all the mainnet ones on mainnet, the
+    /// ETH / USD and EUR / USD feeds of the network otherwise.
+    pub fn feed_registry(&self, profile: &Profile) -> Result<FeedRegistry> {
+        let contracts = self.contracts(profile)?;
+        let mut registry = match profile.network.as_deref().unwrap_or(DEFAULT_NETWORK) {
+            DEFAULT_NETWORK => FeedRegistry::mainnet(),
+            _ => FeedRegistry::new(),
+        };
+        registry.add("ETH/USD", contracts.chainlink)?;
+        if let Some(eur_usd) = contracts.eur_usd {
+            registry.add("EUR/USD", eur_usd)?;
+        }
+        Ok(registry)
+    }
+
+    /// The contracts of the network of the profile, mainnet ones unless configured.
+    pub fn contracts(&self, profile: &Profile) -> Result<Contracts> {
+        let network = profile.network.as_deref().unwrap_or(DEFAULT_NETWORK);
+        match self.networks.get(network) {
+            Some(contracts) => Ok(*contracts),
+            None if network == DEFAULT_NETWORK => Ok(Contracts::mainnet()),
+            None => Err(BermudaError::Config(format!("unknown network {}", network))),
+        }
+    }
+}
+
+impl Profile {
+    pub fn watched_tokens(&self) -> Result<Option<Vec<WatchedToken>>> {
+        match &self.tokens {
+            Some(tokens) => Ok(Some(
+                tokens.iter().map(|token| token.parse()).collect::<Result<_>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_profile = "main"
+
+[networks.goerli]
+aave = "0x4bd5643ac6f66a5237e18bfa7d47cf22f1c9f210"
+compound = "0x3ee77595a8459e93c2888b13ada354017d5bd4b8"
+weth = "0x42a71137c09ae83d8d05974960fd607d40033499"
+chainlink = "0xd4a33860578de61dbabdc8bfdb98fd742fa7028e"
+
+[profiles.main]
+node = "http://localhost:8545"
+smart_wallet = "0x0000000000000000000000000000000000000001"
+tokens = ["0x6b175474e89094c44da98b954eedeac495271d0f:stable"]
+history = "/var/lib/bermuda/main.sqlite"
+
+[profiles.main.strategy]
+target_ratio = 1.6
+
+[profiles.main.oracle]
+max_age = 3600
+
+[profiles.main.costs]
+slippage = 0.01
+
+[profiles.test]
+strategy = { rebalance_band = [1.3, 1.7] }
+network = "goerli"
+"#;
+
+    #[test]
+    fn profile_tests() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let main = config.profile(None).unwrap();
+        assert_eq!(main.smart_wallet.as_deref(), Some("0x0000000000000000000000000000000000000001"));
+        assert_eq!(main.watched_tokens().unwrap().unwrap().len(), 1);
+        assert_eq!(main.strategy.target_ratio, 1.6);
+        assert!(main.strategy.params().validate(0.8).is_ok());
+        assert_eq!((main.strategy.exponent().unwrap() * 100.0).round(), 267.0);
+        assert_eq!(main.oracle.unwrap().max_age, Some(3600));
+        assert_eq!(main.costs.slippage, 0.01);
+        assert_eq!(main.costs.gas_limit, REBALANCE_GAS);
+        assert_eq!(main.history, Some(PathBuf::from("/var/lib/bermuda/main.sqlite")));
+        assert_eq!(config.contracts(&main).unwrap(), Contracts::mainnet());
+
+        let test = config.profile(Some("test")).unwrap();
+        assert_eq!((test.strategy.exponent().unwrap() * 100.0).round(), 316.0);
+        assert_eq!(
+            config.contracts(&test).unwrap().aave,
+            "4bd5643ac6f66a5237e18bfa7d47cf22f1c9f210".parse().unwrap()
+        );
+        assert!(config.profile(Some("unknown")).is_err());
+
+        let receiver = Address::from_low_u64_be(1);
+        assert_eq!(config.contracts(&main).unwrap().rebalance(receiver).unwrap().swap_router, SWAP_ROUTER_ADDRESS.parse().unwrap());
+        // mainnet addresses are not used on another network
+        assert!(matches!(config.contracts(&test).unwrap().rebalance(receiver), Err(BermudaError::Config(_))));
+
+        assert!(config.feed_registry(&main).unwrap().route("EUR/USD").is_ok());
+        let registry = config.feed_registry(&test).unwrap();
+        assert_eq!(registry.route("ETH/USD").unwrap()[0].feed, config.contracts(&test).unwrap().chainlink);
+        assert!(registry.route("EUR/USD").is_err());
+    }
+
+    #[test]
+    fn exponent_tests() {
+        let strategy = StrategyConfig::default();
+        assert_eq!(strategy.exponent().unwrap(), 3.0);
+        let band = StrategyConfig { rebalance_band: Some([1.5, 1.5]), target_ratio: 2.0, ..strategy };
+        assert_eq!(band.exponent().unwrap(), 3.0);
+        let fixed = StrategyConfig { exp_factor: Some(2.6), ..band };
+        assert_eq!(fixed.exponent().unwrap(), 2.6);
+        let liquidated = StrategyConfig { target_ratio: 1.0, ..strategy };
+        assert!(matches!(liquidated.exponent(), Err(BermudaError::Model(_))));
+    }
+
+    #[test]
+    fn empty_config_has_a_default_profile() {
+        let config = Config::parse("").unwrap();
+        let profile = config.profile(None).unwrap();
+        assert_eq!(profile.node, None);
+        assert_eq!(profile.strategy, StrategyConfig::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::parse("[profiles.main]\nnodes = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use super::error::{BermudaError, Result};
+use ethabi::{Address, Contract, Int, Token, Uint};
+
+fn token(tokens: &[Token], index: usize) -> Result<&Token> {
+    tokens.get(index).ok_or_else(|| {
+        BermudaError::Abi(format!("expected at least {} values, got {}", index + 1, tokens.len()))
+    })
+}
+
+fn mismatch(expected: &str, index: usize, token: &Token) -> BermudaError {
+    BermudaError::Abi(format!("expected {} at position {}, got {:?}", expected, index, token))
+}
+
+pub(crate) fn uint(tokens: &[Token], index: usize) -> Result<Uint> {
+    let token = token(tokens, index)?;
+    token.clone().into_uint().ok_or_else(|| mismatch("an uint", index, token))
+}
+
+pub(crate) fn int(tokens: &[Token], index: usize) -> Result<Int> {
+    let token = token(tokens, index)?;
+    token.clone().into_int().ok_or_else(|| mismatch("an int", index, token))
+}
+
+pub(crate) fn address(tokens: &[Token], index: usize) -> Result<Address> {
+    let token = token(tokens, index)?;
+    token.clone().into_address().ok_or_else(|| mismatch("an address", index, token))
+}
+
+pub(crate) fn boolean(tokens: &[Token], index: usize) -> Result<bool> {
+    let token = token(tokens, index)?;
+    token.clone().into_bool().ok_or_else(|| mismatch("a bool", index, token))
+}
+
+pub(crate) fn tuple(tokens: &[Token], index: usize) -> Result<Vec<Token>> {
+    let token = token(tokens, index)?;
+    token.clone().into_tuple().ok_or_else(|| mismatch("a tuple", index, token))
+}
+
+/// Parse an address, with or without its `0x` prefix.
+pub(crate) fn parse_address(address: &str) -> Result<Address> {
+    address
+        .strip_prefix("0x")
+        .unwrap_or(address)
+        .parse()
+        .map_err(|_| BermudaError::Address(address.to_string()))
+}
+
+/// Decode the reason of a revert from its `Error(string)` or `Panic(uint256)` payload.
+pub(crate) fn revert_reason(data: &[u8]) -> Option<String> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (selector, payload) = data.split_at(4);
+    match selector {
+        [0x08, 0xc3, 0x79, 0xa0] => ethabi::decode(&[ethabi::ParamType::String], payload)
+            .ok()?
+            .pop()?
+            .into_string(),
+        [0x4e, 0x48, 0x7b, 0x71] => ethabi::decode(&[ethabi::ParamType::Uint(256)], payload)
+            .ok()?
+            .pop()?
+            .into_uint()
+            .map(|code| format!("panic {:#x}", code)),
+        _ => None,
+    }
+}
+
+/// The reason of a revert, from `Error(string)`, `Panic(uint256)` or a custom error of `contracts`.
+pub(crate) fn decode_revert(data: &[u8], contracts: &[Contract]) -> Option<String> {
+    revert_reason(data).or_else(|| {
+        let (selector, payload) = (data.get(..4)?, &data[4..]);
+        let error = contracts
+            .iter()
+            .flat_map(|contract| contract.errors.values().flatten())
+            .find(|error| &error.signature()[..4] == selector)?;
+        let tokens = error.decode(payload).unwrap_or_default();
+        let tokens: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+        Some(format!("{}({})", error.name, tokens.join(", ")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revert_reason_tests() {
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethabi::encode(&[Token::String(String::from("Not enough collateral"))]));
+        assert_eq!(revert_reason(&data), Some(String::from("Not enough collateral")));
+
+        let mut data = vec![0x4e, 0x48, 0x7b, 0x71];
+        data.extend(ethabi::encode(&[Token::Uint(Uint::from(0x11))]));
+        assert_eq!(revert_reason(&data), Some(String::from("panic 0x11")));
+
+        assert_eq!(revert_reason(&[]), None);
+    }
+
+    #[test]
+    fn decode_revert_tests() {
+        let comet = Contract::load(&include_bytes!("abi/comet.abi")[..]).unwrap();
+        let contracts = std::slice::from_ref(&comet);
+        let data = comet.error("NotCollateralized").unwrap().encode(&[]).unwrap();
+        assert_eq!(decode_revert(&data, contracts), Some(String::from("NotCollateralized()")));
+
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethabi::encode(&[Token::String(String::from("Too little received"))]));
+        assert_eq!(decode_revert(&data, contracts), Some(String::from("Too little received")));
+        assert_eq!(decode_revert(&[0xde, 0xad, 0xbe, 0xef], contracts), None);
+    }
+
+    #[test]
+    fn decode_mismatch_is_an_error() {
+        let tokens = vec![Token::Bool(true)];
+        assert!(matches!(uint(&tokens, 0), Err(BermudaError::Abi(_))));
+        assert!(matches!(uint(&tokens, 1), Err(BermudaError::Abi(_))));
+        assert!(matches!(parse_address("0xnope"), Err(BermudaError::Address(_))));
+    }
+}
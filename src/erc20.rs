@@ -1,30 +1,119 @@
-use super::blockchain::HttpBlockchainReader;
+use super::blockchain::BlockchainReader;
+use super::amount::Amount;
+use super::decode::uint;
+use super::error::{BermudaError, Result};
+use super::multicall::Batch;
 use ethabi::Uint;
 use ethabi::{Address, Contract, Token};
-use std::error::Error;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
 
+/// The description of a token, as read from its contract.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub address: Address,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+}
+
+/// The calls queued by `ERC20::add_info`.
+#[derive(Debug, Clone, Copy)]
+pub struct InfoCalls {
+    decimals: usize,
+    symbol: usize,
+    name: usize,
+}
 
-pub struct ERC20<'a> {
-    blockchain_reader: &'a HttpBlockchainReader,
+/// An ERC20 token. Its decimals, symbol and name are read once, on first use.
+pub struct ERC20<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
     address: Address,
     contract: Contract,
-    decimals: Option<usize>,
+    bytes32_contract: Contract,
+    decimals: Cell<Option<u8>>,
+    symbol: RefCell<Option<String>>,
+    name: RefCell<Option<String>>,
 }
-impl<'a> ERC20<'a> {
-    pub fn new(blockchain_reader: &'a HttpBlockchainReader, address: Address) -> Result<Self, Box<dyn Error>> {
+impl<'a, R: BlockchainReader> ERC20<'a, R> {
+    pub fn new(blockchain_reader: &'a R, address: Address) -> Result<Self> {
         let abi: &[u8] = include_bytes!("abi/erc20.abi");
         let contract: Contract = Contract::load(abi)?;
-        let decimals = None;
+        // tokens like MKR return their symbol and name as bytes32
+        let bytes32_abi: &[u8] = include_bytes!("abi/erc20_bytes32.abi");
+        let bytes32_contract: Contract = Contract::load(bytes32_abi)?;
         let erc20 = Self {
             blockchain_reader,
             address,
             contract,
-            decimals,
+            bytes32_contract,
+            decimals: Cell::new(None),
+            symbol: RefCell::new(None),
+            name: RefCell::new(None),
         };
         Ok(erc20)
     }
 
-    pub async fn get_value(&self, &address: &Address) -> Result<f64, Box<dyn Error>> {
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub async fn get_info(&self) -> Result<TokenInfo> {
+        Ok(TokenInfo {
+            address: self.address,
+            symbol: self.get_symbol().await?,
+            name: self.get_name().await?,
+            decimals: self.get_decimals().await?,
+        })
+    }
+
+    pub async fn get_symbol(&self) -> Result<String> {
+        if let Some(symbol) = self.symbol.borrow().clone() {
+            return Ok(symbol);
+        }
+        let symbol = self.get_string("symbol").await?;
+        self.symbol.replace(Some(symbol.clone()));
+        Ok(symbol)
+    }
+
+    pub async fn get_name(&self) -> Result<String> {
+        if let Some(name) = self.name.borrow().clone() {
+            return Ok(name);
+        }
+        let name = self.get_string("name").await?;
+        self.name.replace(Some(name.clone()));
+        Ok(name)
+    }
+
+    /// Read a string, falling back to the bytes32 ABI when the token does not return a string.
+    async fn get_string(&self, function: &str) -> Result<String> {
+        let string = self
+            .blockchain_reader
+            .call_function(&self.contract, &self.address, function, &[])
+            .await;
+        match string {
+            Err(BermudaError::Abi(_)) => {
+                let tokens = self
+                    .blockchain_reader
+                    .call_function(&self.bytes32_contract, &self.address, function, &[])
+                    .await?;
+                match tokens.into_iter().next().and_then(|token| token.into_fixed_bytes()) {
+                    Some(bytes) => {
+                        let bytes: Vec<u8> = bytes.into_iter().take_while(|&byte| byte != 0).collect();
+                        Ok(String::from_utf8_lossy(&bytes).into_owned())
+                    }
+                    None => Err(BermudaError::Abi(format!("{} of {:#x} is not a bytes32", function, self.address))),
+                }
+            }
+            string => match string?.into_iter().next().and_then(|token| token.into_string()) {
+                Some(string) => Ok(string),
+                None => Err(BermudaError::Abi(format!("{} of {:#x} is not a string", function, self.address))),
+            },
+        }
+    }
+
+    /// The exact balance of the address.
+    pub async fn get_balance(&self, &address: &Address) -> Result<Amount> {
         let tokens = self
             .blockchain_reader
             .call_function(
@@ -35,18 +124,56 @@ impl<'a> ERC20<'a> {
             )
             .await?;
 
-        let token = tokens[0].clone().into_uint();
-        let token = token.unwrap();
+        self.decode_balance(&tokens).await
+    }
+
+    /// Queue the `balanceOf` of `address` in `batch`, its output is read by `decode_balance`.
+    pub fn add_balance<'b>(&'b self, batch: &mut Batch<'b>, address: &Address) -> Result<usize> {
+        batch.add(&self.contract, &self.address, "balanceOf", &[Token::Address(*address)])
+    }
+
+    pub async fn decode_balance(&self, tokens: &[Token]) -> Result<Amount> {
+        Ok(Amount::new(uint(tokens, 0)?, self.get_decimals().await?))
+    }
 
-        let decimals = self.get_decimals().await?;
-        let token_value =
-            (token.as_u128() as f64) / Uint::exp10(decimals).as_u128() as f64;
+    /// Queue the decimals, symbol and name in `batch`, to be kept by `cache_info`.
+    pub fn add_info<'b>(&'b self, batch: &mut Batch<'b>) -> Result<InfoCalls> {
+        Ok(InfoCalls {
+            decimals: batch.add(&self.contract, &self.address, "decimals", &[])?,
+            symbol: batch.add(&self.contract, &self.address, "symbol", &[])?,
+            name: batch.add(&self.contract, &self.address, "name", &[])?,
+        })
+    }
+
+    /// Keep what the calls of `add_info` returned. Those that failed, like the symbol of a
+    /// bytes32 token, are read again on first use.
+    pub fn cache_info(&self, calls: &InfoCalls, results: &[Result<Vec<Token>>]) {
+        let string = |index: usize| match &results[index] {
+            Ok(tokens) => tokens.first().cloned().and_then(|token| token.into_string()),
+            Err(_) => None,
+        };
+        if let Ok(tokens) = &results[calls.decimals] {
+            if let Ok(value) = uint(tokens, 0) {
+                if value <= Uint::from(77) {
+                    self.decimals.set(Some(value.as_u32() as u8));
+                }
+            }
+        }
+        if let Some(symbol) = string(calls.symbol) {
+            self.symbol.replace(Some(symbol));
+        }
+        if let Some(name) = string(calls.name) {
+            self.name.replace(Some(name));
+        }
+    }
 
-        Ok(token_value)
+    pub async fn get_value(&self, address: &Address) -> Result<f64> {
+        let balance = self.get_balance(address).await?;
+        Ok(balance.to_f64())
     }
 
-    async fn get_decimals(&self) -> Result<usize, Box<dyn Error>> {
-        let decimals = match self.decimals {
+    pub async fn get_decimals(&self) -> Result<u8> {
+        let decimals = match self.decimals.get() {
             Some(decimals) => decimals,
             None =>{
                 let tokens = self
@@ -59,12 +186,85 @@ impl<'a> ERC20<'a> {
                         )
                     .await?;
 
-                let token = tokens[0].clone().into_uint();
-                let value = token.unwrap();
-                let decimals = value.as_u32() as usize;
+                let value = uint(&tokens, 0)?;
+                if value > Uint::from(77) {
+                    return Err(BermudaError::Overflow(format!("{} decimals", value)));
+                }
+                let decimals = value.as_u32() as u8;
+                self.decimals.set(Some(decimals));
                 decimals
-            } 
+            }
         };
         Ok(decimals)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockchainReader, MockBlockchainReader};
+
+    #[tokio::test]
+    async fn get_info_is_read_once() {
+        let dai: Address = "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap();
+        let contract = Contract::load(&include_bytes!("abi/erc20.abi")[..]).unwrap();
+        let mut reader = MockBlockchainReader::new();
+        let mut mock = |name: &str, output: Token| {
+            reader.mock_call(&contract, &dai, name, &[], &[output]).unwrap();
+        };
+        mock("decimals", Token::Uint(Uint::from(18)));
+        mock("symbol", Token::String(String::from("DAI")));
+        mock("name", Token::String(String::from("Dai Stablecoin")));
+        let erc20 = ERC20::new(&reader, dai).unwrap();
+
+        let info = erc20.get_info().await.unwrap();
+        assert_eq!(info.symbol, "DAI");
+        assert_eq!(info.name, "Dai Stablecoin");
+        assert_eq!(info.decimals, 18);
+        assert_eq!(erc20.get_info().await.unwrap(), info);
+        assert_eq!(reader.eth_call_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn batched_info_is_cached() {
+        let dai: Address = "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap();
+        let owner = Address::from_low_u64_be(1);
+        let contract = Contract::load(&include_bytes!("abi/erc20.abi")[..]).unwrap();
+        let mut reader = MockBlockchainReader::new();
+        let mut mock = |name: &str, params: &[Token], output: Token| {
+            reader.mock_call(&contract, &dai, name, params, &[output]).unwrap();
+        };
+        mock("decimals", &[], Token::Uint(Uint::from(18)));
+        mock("symbol", &[], Token::String(String::from("DAI")));
+        mock("name", &[], Token::String(String::from("Dai Stablecoin")));
+        mock("balanceOf", &[Token::Address(owner)], Token::Uint(Uint::exp10(18) * 5));
+        let erc20 = ERC20::new(&reader, dai).unwrap();
+
+        let mut batch = Batch::new();
+        let balance_call = erc20.add_balance(&mut batch, &owner).unwrap();
+        let info_calls = erc20.add_info(&mut batch).unwrap();
+        let results = reader.call_batch(&batch).await.unwrap();
+        erc20.cache_info(&info_calls, &results);
+        let calls = reader.eth_call_count();
+
+        let balance = erc20.decode_balance(&results[balance_call].clone().unwrap()).await.unwrap();
+        assert_eq!(balance.to_f64(), 5.0);
+        assert_eq!(erc20.get_info().await.unwrap().symbol, "DAI");
+        assert_eq!(reader.eth_call_count(), calls);
+    }
+
+    #[tokio::test]
+    async fn get_symbol_falls_back_to_bytes32() {
+        let mkr: Address = "9f8f72aa9304c8b593d555f12ef6589cc3a579a2".parse().unwrap();
+        let contract = Contract::load(&include_bytes!("abi/erc20_bytes32.abi")[..]).unwrap();
+        let mut symbol = b"MKR".to_vec();
+        symbol.resize(32, 0);
+        let mut reader = MockBlockchainReader::new();
+        reader
+            .mock_call(&contract, &mkr, "symbol", &[], &[Token::FixedBytes(symbol)])
+            .unwrap();
+        let erc20 = ERC20::new(&reader, mkr).unwrap();
+
+        assert_eq!(erc20.get_symbol().await.unwrap(), "MKR");
+    }
+}
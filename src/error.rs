@@ -0,0 +1,149 @@
+use ethabi::Address;
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong while reading the portfolio from the chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BermudaError {
+    /// The node could not be reached, or its answer could not be understood.
+    Transport(String),
+    /// The node answered with a JSON-RPC error object.
+    Rpc { code: i64, message: String },
+    /// The call reverted, with the decoded reason when the contract gave one.
+    Revert { contract: Address, reason: Option<String> },
+    /// The contract answer does not match the bundled ABI.
+    Abi(String),
+    /// The string is not a valid ethereum address.
+    Address(String),
+    /// The value does not fit in the numeric type it is converted to.
+    Overflow(String),
+    /// An argument given by the user is not valid.
+    Input(String),
+    /// The config file cannot be read, or is not valid.
+    Config(String),
+    /// The price feed has not been updated recently enough, or its last round is incomplete.
+    StaleOracle { feed: Address, reason: String },
+    /// The price feed answered outside of the configured sanity band.
+    OracleOutOfRange { feed: Address, answer: f64, min: f64, max: f64 },
+    /// The strategy cannot be applied to the portfolio.
+    Model(String),
+    /// An alert could not be sent.
+    Notify(String),
+    /// The snapshot history cannot be read or written.
+    Store(String),
+    /// A value cannot be serialized to JSON, or read back from it.
+    Json(String),
+}
+
+pub type Result<T, E = BermudaError> = std::result::Result<T, E>;
+
+impl BermudaError {
+    /// Classify the failure of a JSON-RPC request: the node either answered with an error
+    /// object, or could not be reached at all.
+    pub(crate) fn from_rpc<E: fmt::Display>(error: E) -> Self {
+        let message = error.to_string();
+        let object = message
+            .find('{')
+            .and_then(|start| serde_json::from_str::<serde_json::Value>(&message[start..]).ok());
+        match object {
+            Some(object) if object["code"].is_i64() => BermudaError::Rpc {
+                code: object["code"].as_i64().unwrap_or_default(),
+                message: object["message"].as_str().unwrap_or_default().to_string(),
+            },
+            _ => BermudaError::Transport(message),
+        }
+    }
+
+    /// Turn the `execution reverted` error object a node answers to a failing `eth_call`
+    /// into a revert of the contract.
+    pub(crate) fn into_revert(self, contract: &Address) -> Self {
+        match self {
+            BermudaError::Rpc { message, .. } if message.starts_with("execution reverted") => {
+                let reason = message
+                    .strip_prefix("execution reverted: ")
+                    .map(|reason| reason.to_string());
+                BermudaError::Revert { contract: *contract, reason }
+            }
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for BermudaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BermudaError::Transport(message) => {
+                write!(f, "cannot talk to the ethereum node ({}), check the --node url", message)
+            }
+            BermudaError::Rpc { code, message } => {
+                write!(f, "the ethereum node answered with error {}: {}", code, message)
+            }
+            BermudaError::Revert { contract, reason: Some(reason) } => {
+                write!(f, "call to {:#x} reverted: {}", contract, reason)
+            }
+            BermudaError::Revert { contract, reason: None } => {
+                write!(f, "call to {:#x} reverted without a reason", contract)
+            }
+            BermudaError::Abi(message) => write!(f, "unexpected contract answer: {}", message),
+            BermudaError::Address(address) => write!(f, "invalid ethereum address: {}", address),
+            BermudaError::Overflow(message) => write!(f, "numeric overflow: {}", message),
+            BermudaError::Input(message) => write!(f, "{}", message),
+            BermudaError::Config(message) => write!(f, "invalid config: {}", message),
+            BermudaError::StaleOracle { feed, reason } => {
+                write!(f, "price feed {:#x} is stale: {}", feed, reason)
+            }
+            BermudaError::OracleOutOfRange { feed, answer, min, max } => write!(
+                f,
+                "price feed {:#x} answered {}, outside of [{}, {}], check the oracle limits of the profile",
+                feed, answer, min, max
+            ),
+            BermudaError::Model(message) => write!(f, "cannot model the portfolio: {}", message),
+            BermudaError::Notify(message) => write!(f, "cannot send the alert: {}", message),
+            BermudaError::Store(message) => write!(f, "cannot use the snapshot history: {}", message),
+            BermudaError::Json(message) => write!(f, "invalid JSON: {}", message),
+        }
+    }
+}
+
+impl Error for BermudaError {}
+
+impl From<ethabi::Error> for BermudaError {
+    fn from(error: ethabi::Error) -> Self {
+        BermudaError::Abi(error.to_string())
+    }
+}
+
+impl From<tera::Error> for BermudaError {
+    fn from(error: tera::Error) -> Self {
+        BermudaError::Model(error.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for BermudaError {
+    fn from(error: rusqlite::Error) -> Self {
+        BermudaError::Store(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BermudaError {
+    fn from(error: serde_json::Error) -> Self {
+        BermudaError::Json(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_rpc_tests() {
+        assert_eq!(
+            BermudaError::from_rpc(r#"rpc error: {"code":-32602,"message":"invalid argument 0"}"#),
+            BermudaError::Rpc { code: -32602, message: String::from("invalid argument 0") }
+        );
+        assert_eq!(
+            BermudaError::from_rpc("connection refused"),
+            BermudaError::Transport(String::from("connection refused"))
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use super::error::{BermudaError, Result};
+use ethabi::{Address, Uint};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The answer of one past Chainlink round.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PricePoint {
+    /// The proxy round id, `phase << 64 | aggregator round`.
+    pub round_id: Uint,
+    /// Unix timestamp of the answer.
+    pub timestamp: u64,
+    pub price: f64,
+}
+
+/// Past rounds of a feed kept on disk as JSON. Answered rounds never change, so they are
+/// only read once from the node.
+#[derive(Debug, Default)]
+pub struct RoundCache {
+    path: Option<PathBuf>,
+    rounds: BTreeMap<Uint, PricePoint>,
+}
+
+impl RoundCache {
+    /// A cache that is not saved anywhere.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// The cache stored at `path`, empty if the file does not exist yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let rounds = match path.exists() {
+            true => {
+                let content = fs::read_to_string(path).map_err(|error| cache_error(path, error))?;
+                let points: Vec<PricePoint> =
+                    serde_json::from_str(&content).map_err(|error| cache_error(path, error))?;
+                points.into_iter().map(|point| (point.round_id, point)).collect()
+            }
+            false => BTreeMap::new(),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            rounds,
+        })
+    }
+
+    /// `~/.cache/bermuda/chainlink-<feed>.json`.
+    pub fn default_path(feed: &Address) -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| {
+            Path::new(&home)
+                .join(".cache")
+                .join("bermuda")
+                .join(format!("chainlink-{:#x}.json", feed))
+        })
+    }
+
+    pub fn get(&self, round_id: &Uint) -> Option<&PricePoint> {
+        self.rounds.get(round_id)
+    }
+
+    pub fn insert(&mut self, point: PricePoint) {
+        self.rounds.insert(point.round_id, point);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rounds.is_empty()
+    }
+
+    /// Write the cache back to its file, if it has one.
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| cache_error(path, error))?;
+        }
+        let points: Vec<&PricePoint> = self.rounds.values().collect();
+        fs::write(path, serde_json::to_string(&points)?).map_err(|error| cache_error(path, error))
+    }
+}
+
+/// Parse a unix timestamp, or a `YYYY-MM-DD` date at midnight UTC.
+pub fn parse_timestamp(value: &str) -> Result<u64> {
+    let invalid = || BermudaError::Input(format!("invalid date {}, expected YYYY-MM-DD or a unix timestamp", value));
+    if let Ok(timestamp) = value.parse() {
+        return Ok(timestamp);
+    }
+    let parts: Vec<i64> = value
+        .split('-')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_>>()?;
+    let (year, month, day) = match parts[..] {
+        [year, month @ 1..=12, day] if year >= 1970 && (1..=days_in_month(year, month)).contains(&day) => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    // days from the civil calendar, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days as u64 * 86_400)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse the end of a range: a `YYYY-MM-DD` date is its last second, a unix timestamp itself.
+pub fn parse_end_timestamp(value: &str) -> Result<u64> {
+    let timestamp = parse_timestamp(value)?;
+    match value.parse::<u64>() {
+        Ok(_) => Ok(timestamp),
+        Err(_) => Ok(timestamp + 86_399),
+    }
+}
+
+/// Format a unix timestamp as `YYYY-MM-DD HH:MM` UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    // the inverse of parse_timestamp
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let seconds = timestamp % 86_400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
+
+fn cache_error<E: std::fmt::Display>(path: &Path, error: E) -> BermudaError {
+    BermudaError::Input(format!("cannot use the round cache {}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timestamp_tests() {
+        assert_eq!(parse_timestamp("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_timestamp("2020-03-12").unwrap(), 1_583_971_200);
+        assert_eq!(parse_timestamp("2024-02-29").unwrap(), 1_709_164_800);
+        assert_eq!(parse_timestamp("1600000000").unwrap(), 1_600_000_000);
+        assert_eq!(parse_timestamp("2000-02-29").unwrap(), 951_782_400);
+        assert!(parse_timestamp("2020-13-01").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+        // days past the end of their month
+        assert!(parse_timestamp("2021-02-29").is_err());
+        assert!(parse_timestamp("2021-02-31").is_err());
+        assert!(parse_timestamp("2021-04-31").is_err());
+        assert!(parse_timestamp("2100-02-29").is_err());
+        assert!(parse_timestamp("2021-01-00").is_err());
+
+        assert_eq!(parse_end_timestamp("2020-03-12").unwrap(), 1_583_971_200 + 86_399);
+        assert_eq!(parse_end_timestamp("1600000000").unwrap(), 1_600_000_000);
+        assert!(parse_end_timestamp("2020-03-32").is_err());
+    }
+
+    #[test]
+    fn format_timestamp_tests() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_709_164_800 + 3_660), "2024-02-29 01:01");
+        assert_eq!(format_timestamp(parse_timestamp("2000-03-01").unwrap()), "2000-03-01 00:00");
+        assert_eq!(format_timestamp(1_583_971_200 - 60), "2020-03-11 23:59");
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("bermuda-cache-{}.json", std::process::id()));
+        let point = PricePoint {
+            round_id: (Uint::from(2) << 64) | Uint::from(7),
+            timestamp: 1_700_000_000,
+            price: 2000.5,
+        };
+        let mut cache = RoundCache::open(&path).unwrap();
+        assert!(cache.is_empty());
+        cache.insert(point);
+        cache.save().unwrap();
+
+        let cache = RoundCache::open(&path).unwrap();
+        assert_eq!(cache.get(&point.round_id), Some(&point));
+        fs::remove_file(&path).unwrap();
+    }
+}
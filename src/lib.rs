@@ -4,15 +4,46 @@ mod blockchain;
 mod erc20;
 mod chainlink;
 mod smart_wallet;
+mod multicall;
+mod mock;
+mod decode;
+mod error;
+mod amount;
+mod watchlist;
+mod config;
+mod history;
+mod backtest;
+mod calibration;
+mod transaction;
+mod simulation;
+mod portfolio;
+mod watch;
+mod alert;
+mod store;
 
 use std::collections::HashMap;
 
-pub use crate::blockchain::HttpBlockchainReader;
-pub use crate::aave::Aave;
-pub use crate::compound::Compound;
-pub use crate::chainlink::Chainlink;
+pub use crate::blockchain::{BlockTag, BlockchainReader, HttpBlockchainReader, TransactionReceipt};
+pub use crate::mock::MockBlockchainReader;
+pub use crate::error::BermudaError;
+pub use crate::amount::Amount;
+pub use crate::config::{Config, Contracts, CostConfig, Profile, StrategyConfig, DEFAULT_NODE};
+pub use crate::watchlist::{get_holdings, Holding, Pricing, WatchedToken};
+pub use crate::aave::{Aave, AaveAccount};
+pub use crate::compound::{Compound, CometAccount};
+pub use crate::chainlink::{Chainlink, FeedLeg, FeedRegistry, OracleLimits, PriceReading};
 pub use crate::smart_wallet::SmartWallet;
-pub use crate::erc20::ERC20;
+pub use crate::erc20::{InfoCalls, TokenInfo, ERC20};
+pub use crate::multicall::Batch;
+pub use crate::history::{format_timestamp, parse_end_timestamp, parse_timestamp, PricePoint, RoundCache};
+pub use crate::transaction::{build_rebalance, RebalanceContracts, RebalanceTransaction, Step, TransactionOptions, UnsignedTransaction};
+pub use crate::simulation::Simulator;
+pub use crate::portfolio::{prediction_grid, rebalance_prices, Portfolio, PortfolioConfig, PortfolioSnapshot};
+pub use crate::watch::{render_watch, WatchValues};
+pub use crate::alert::{check_error, check_rules, Alert, AlertConfig, AlertEngine, AlertRule, CommandNotifier, Notifier, NotifierConfig, SmtpNotifier, WebhookNotifier};
+pub use crate::store::{SnapshotStore, StoredHolding, StoredSnapshot};
+pub use crate::calibration::{exponent_for_band, exponent_for_ratio, fit_exponent};
+pub use crate::backtest::{backtest, parse_price_csv, Backtest, BacktestPoint};
 
 
 use serde::{Serialize, Deserialize};
@@ -24,7 +55,20 @@ use num_format::{Locale, ToFormattedString};
 #[macro_use]
 extern crate tera;
 
-const EXP_FACTOR:f64 = 2.6;
+/// The collateral / debt ratio each loan is brought back to by `initialize_bermuda`.
+pub const TARGET_RATIO:f64 = 1.5;
+/// The part of the portfolio taken out as USDC by `initialize_bermuda`.
+pub const KEEP_RATIO:f64 = 0.1;
+/// The part of the invested value put in the short leg by `initialize_bermuda`.
+pub const SHORT_SHARE:f64 = 2.0 / 3.0;
+/// The price the swaps of a rebalance may lose, 0.5%.
+pub const SLIPPAGE:f64 = 0.005;
+/// The gas a rebalance transaction is expected to use.
+pub const REBALANCE_GAS:u64 = 1_200_000;
+/// Rebalance when the long leg is worth this many times the short one.
+pub const REBALANCE_UP_RATIO:f64 = 6.0;
+/// Rebalance when the short leg is worth this many times the long one.
+pub const REBALANCE_DOWN_RATIO:f64 = 36.0;
 
 pub fn humanize(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
     let num = try_get_value!("humanize", "value", f64, value.clone());
@@ -49,13 +93,23 @@ pub struct Loan {
     pub debt: f64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Loan {
+    /// The loan as used by the price model, from the exact amounts read on chain.
+    pub fn from_amounts(collateral: &Amount, debt: &Amount) -> Loan {
+        Loan {
+            collateral: collateral.to_f64(),
+            debt: debt.to_f64(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Currency {
     ETH,
     USDC
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Equalize {
     pub eth_price: f64,
     pub flash_loan_currency: Currency,
@@ -67,21 +121,66 @@ pub struct Equalize {
     pub long_debt_delta_usdc: f64,
 }
 
-pub fn initialize_bermuda(short: Loan, long: Loan, eth_price: f64) -> Result<Equalize> {
-    // the target ratio coll / debt for each loan
-    let target_ratio = 1.5;
-    // the part of the treasure to extract
-    let keep_ratio = 0.1;
+/// How `initialize_bermuda` levers and splits the portfolio.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct StrategyParams {
+    /// The collateral / debt ratio both loans are brought back to.
+    pub target_ratio: f64,
+    /// The part of the portfolio taken out as USDC.
+    pub keep_ratio: f64,
+    /// The parts of the invested value put in the short and long legs, summing to 1.
+    pub short_share: f64,
+    pub long_share: f64,
+}
+
+impl Default for StrategyParams {
+    fn default() -> Self {
+        StrategyParams {
+            target_ratio: TARGET_RATIO,
+            keep_ratio: KEEP_RATIO,
+            short_share: SHORT_SHARE,
+            long_share: 1.0 - SHORT_SHARE,
+        }
+    }
+}
+
+impl StrategyParams {
+    /// Check the params can be used with loans allowing at most `ltv` of debt per collateral,
+    /// 1.0 when only the math matters.
+    pub fn validate(&self, ltv: f64) -> Result<()> {
+        if ltv <= 0.0 || self.target_ratio * ltv <= 1.0 {
+            return Err(format!(
+                "a target ratio of {} cannot be borrowed with a loan to value of {}, it must be above {:.4}",
+                self.target_ratio, ltv, 1.0 / ltv
+            ).into());
+        }
+        if !(0.0..1.0).contains(&self.keep_ratio) {
+            return Err(format!("the keep ratio must be in [0, 1), not {}", self.keep_ratio).into());
+        }
+        if self.short_share < 0.0 || self.long_share < 0.0 || (self.short_share + self.long_share - 1.0).abs() > 1e-9 {
+            return Err(format!(
+                "the short and long shares must be positive and sum to 1, not {} + {}",
+                self.short_share, self.long_share
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+/// Plan the rebalance bringing both loans back to the target ratio (coll / debt) of `params`,
+/// after extracting its keep ratio of the portfolio and splitting the rest between the legs.
+pub fn initialize_bermuda(short: Loan, long: Loan, eth_price: f64, params: &StrategyParams) -> Result<Equalize> {
+    params.validate(1.0)?;
+    let StrategyParams { target_ratio, keep_ratio, short_share, long_share } = *params;
     let total_col = short.collateral + long.collateral;
     let total_debt = short.debt + long.debt;
     let total_value = total_col - total_debt;
 
-    // keep 10%
     let keep_eth = total_value * keep_ratio;
     let keep_usdc = keep_eth * eth_price;
     let total_value = total_value - keep_eth;
-    let short_value = total_value * 2.0 / 3.0;
-    let long_value = total_value - short_value;
+    let short_value = total_value * short_share;
+    let long_value = total_value * long_share;
     let target_short = Loan{
         collateral:short_value * target_ratio / (target_ratio - 1.0),
         debt:short_value / (target_ratio - 1.0)
@@ -96,16 +195,16 @@ pub fn initialize_bermuda(short: Loan, long: Loan, eth_price: f64) -> Result<Equ
     };
     let (short_col_delta_usdc, short_debt_delta_eth, long_col_delta_eth, long_debt_delta_usdc) = match flash_loan_currency {
         Currency::ETH => (
-            -1.0 * (target_short.collateral - short.collateral) * eth_price,
-            -1.0 * (target_short.debt - short.debt),
+            -(target_short.collateral - short.collateral) * eth_price,
+            -(target_short.debt - short.debt),
             target_long.collateral - long.collateral,
             (target_long.debt - long.debt) * eth_price,
             ),
         Currency::USDC => (
             (target_short.collateral - short.collateral) * eth_price,
             target_short.debt - short.debt,
-            -1.0 * (target_long.collateral - long.collateral),
-            -1.0 * (target_long.debt - long.debt) * eth_price,
+            -(target_long.collateral - long.collateral),
+            -(target_long.debt - long.debt) * eth_price,
             ),
     };
         
@@ -126,23 +225,83 @@ pub fn initialize_bermuda(short: Loan, long: Loan, eth_price: f64) -> Result<Equ
 
 }
 
+/// What the rebalance planned by an `Equalize` costs. Amounts are in the currency named
+/// next to them, costs are in eth.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RebalanceCosts {
+    pub flash_loan_premium: f64,
+    pub flash_loan_fee: f64,
+    /// The flash loan and its fee, in the flash loan currency.
+    pub flash_loan_repay: f64,
+    /// The currency sold by the swap, the other one is received.
+    pub swap_currency: Currency,
+    /// The most the swap sells with the slippage tolerance.
+    pub swap_amount: f64,
+    /// What the swap buys: the flash loan repay, whatever it costs up to `swap_amount`.
+    pub swap_min_received: f64,
+    pub flash_loan_fee_eth: f64,
+    /// The worst case loss of the swap.
+    pub slippage_cost_eth: f64,
+    pub gas_cost_eth: f64,
+    pub total_cost_eth: f64,
+}
+
+/// Price the rebalance of `equalize` with the flash loan premium, the slippage tolerance of the
+/// swap (0.005 for 0.5%) and the gas cost of the transaction.
+///
+/// The swap only buys back the flash loan and its fee, what is left of the freed amount, the
+/// kept value, stays in the smart wallet.
+pub fn rebalance_costs(equalize: &Equalize, flash_loan_premium: f64, slippage: f64, gas_cost_eth: f64) -> Result<RebalanceCosts> {
+    if !(0.0..1.0).contains(&slippage) {
+        return Err(format!("the slippage must be in [0, 1), not {}", slippage).into());
+    }
+    let price = equalize.eth_price;
+    let flash_loan_fee = equalize.flash_loan_value * flash_loan_premium;
+    let flash_loan_repay = equalize.flash_loan_value + flash_loan_fee;
+    let (swap_currency, repay_eth, swap_amount, flash_loan_fee_eth) = match equalize.flash_loan_currency {
+        // the $ withdrawn from the short and borrowed on the long buy back the flash loaned eth
+        Currency::ETH => (Currency::USDC, flash_loan_repay, flash_loan_repay * price / (1.0 - slippage), flash_loan_fee),
+        // the eth withdrawn from the long and borrowed on the short buy back the flash loaned $
+        Currency::USDC => {
+            let repay_eth = flash_loan_repay / price;
+            (Currency::ETH, repay_eth, repay_eth / (1.0 - slippage), flash_loan_fee / price)
+        }
+    };
+    let slippage_cost_eth = repay_eth * slippage / (1.0 - slippage);
+    Ok(RebalanceCosts {
+        flash_loan_premium,
+        flash_loan_fee,
+        flash_loan_repay,
+        swap_currency,
+        swap_amount,
+        swap_min_received: flash_loan_repay,
+        flash_loan_fee_eth,
+        slippage_cost_eth,
+        gas_cost_eth,
+        total_cost_eth: flash_loan_fee_eth + slippage_cost_eth + gas_cost_eth,
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 enum Direction {
     Up,
     Down
 }
 
-pub fn predict_down(current: &Prediction, base_price:f64) -> Result<Prediction> {
-    predict_next(current, base_price, Direction::Down)
+/// The next prediction below the current price, on the grid of `base_price` times powers of 3.
+/// `exp_factor` is the exponent of the strategy, see `StrategyConfig::exponent`.
+pub fn predict_down(current: &Prediction, base_price:f64, exp_factor: f64) -> Result<Prediction> {
+    predict_next(current, base_price, exp_factor, Direction::Down)
 }
 
-pub fn predict_up(current: &Prediction, base_price:f64) -> Result<Prediction> {
-    predict_next(current, base_price, Direction::Up)
+/// The next prediction above the current price, see `predict_down`.
+pub fn predict_up(current: &Prediction, base_price:f64, exp_factor: f64) -> Result<Prediction> {
+    predict_next(current, base_price, exp_factor, Direction::Up)
 
 }
 
-pub fn predict(current: &Prediction, next_price: f64) -> Result<Prediction> {
-    let exp_factor = EXP_FACTOR;
+/// Project the portfolio at `next_price`: the short leg scales with (price / next_price)^exp_factor.
+pub fn predict(current: &Prediction, next_price: f64, exp_factor: f64) -> Result<Prediction> {
     let next_factor = (current.price / next_price).powf(exp_factor);
     Ok(Prediction{
         price: next_price, 
@@ -152,10 +311,46 @@ pub fn predict(current: &Prediction, next_price: f64) -> Result<Prediction> {
 
 }
 
-fn predict_next(current: &Prediction, base_price:f64, dir:Direction) -> Result<Prediction> {
+/// The eth prices at which each leg gets liquidated. `static` prices keep the loans as they
+/// are now, `model` prices let the equity of each leg follow `predict`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LiquidationPrices {
+    pub short_static: Option<f64>,
+    pub short_model: Option<f64>,
+    pub long_static: Option<f64>,
+    pub long_model: Option<f64>,
+}
+
+/// Solve for the liquidation prices of the short loan (stablecoin collateral, eth debt) and the
+/// long loan (eth collateral, stablecoin debt), both valued in eth at `eth_price`. A leg without
+/// debt is never liquidated.
+///
+/// Under the model the eth debt of the short and the $ debt of the long stay put while the
+/// equity follows `predict`, so a leg is liquidated when its equity falls to `debt * (1 / LT - 1)`.
+/// The model has no price for a leg without equity left, whose model price is None.
+pub fn liquidation_prices(short: &Loan, short_threshold: f64, long: &Loan, long_threshold: f64, eth_price: f64, exp_factor: f64) -> Result<LiquidationPrices> {
+    if short_threshold <= 0.0 || long_threshold <= 0.0 || exp_factor <= 0.0 {
+        return Err("liquidation thresholds and the exponent must be positive".into());
+    }
+    let has_debt = |loan: &Loan| loan.debt > 0.0;
+    let has_equity = |loan: &Loan| loan.collateral > loan.debt;
+    // equity at which a loan with this debt is liquidated
+    let floor = |loan: &Loan, threshold: f64| loan.debt * (1.0 / threshold - 1.0);
+
+    let short_static = Some(eth_price * short.collateral * short_threshold / short.debt).filter(|_| has_debt(short));
+    let short_model = Some(eth_price * ((short.collateral - short.debt) / floor(short, short_threshold)).powf(1.0 / exp_factor))
+        .filter(|_| has_debt(short) && has_equity(short));
+    let long_static = Some(eth_price * long.debt / (long.collateral * long_threshold)).filter(|_| has_debt(long));
+    let long_model = Some(eth_price * (floor(long, long_threshold) / (long.collateral - long.debt)).powf(1.0 / exp_factor))
+        .filter(|_| has_debt(long) && has_equity(long));
+
+    Ok(LiquidationPrices { short_static, short_model, long_static, long_model })
+}
+
+fn predict_next(current: &Prediction, base_price:f64, exp_factor: f64, dir:Direction) -> Result<Prediction> {
     let mut next_price = get_next_price(current.price, base_price, &dir);
 
-    let prediction = predict(current, next_price)?;
+    let prediction = predict(current, next_price, exp_factor)?;
     let next_ratio =  prediction.short / prediction.long;
     // If there is ~ 3x more short than long, this is probably the starting price
     if 2.0 < next_ratio && next_ratio < 5.0 {
@@ -163,7 +358,7 @@ fn predict_next(current: &Prediction, base_price:f64, dir:Direction) -> Result<P
             Direction::Up => next_price*3.0, 
             Direction::Down => next_price/3.0, 
         };
-        return predict(current, next_price)
+        return predict(current, next_price, exp_factor)
     }
     Ok(prediction)
 }
@@ -197,21 +392,105 @@ mod tests {
     use super::*;
     use test_case::test_case;
 
+    /// The exponent the expected predictions were computed with.
+    const EXP_FACTOR: f64 = 2.6;
+
     #[test_case((1200.0, 100.0, 10.0), 1000.0, (2000.0, 26.0, 23.0) ; "up normal")]
     #[test_case((1200.0, 113.221, 6.624), 1000.0, (4000.0, 4.948, 45.471) ; "up must skip")]
     fn predict_up_tests(current: (f64,f64,f64), base_price:f64, expected: (f64,f64,f64)) {
         let current = Prediction{price: current.0, short: current.1, long: current.2};
-        let next = predict_up(&current, base_price).unwrap();
+        let next = predict_up(&current, base_price, EXP_FACTOR).unwrap();
         assert_eq!(next.price, expected.0);
         assert_eq!(next.short.round(), expected.1.round());
         assert_eq!(next.long.round(), expected.2.round());
     }
 
+    #[test_case(1.5, 0.1, 0.6, 0.4, 0.8, true ; "default split")]
+    #[test_case(1.25, 0.1, 0.6, 0.4, 0.8, false ; "at the ltv")]
+    #[test_case(1.5, 0.1, 0.6, 0.3, 0.8, false ; "shares do not sum to 1")]
+    #[test_case(1.5, 1.0, 0.6, 0.4, 0.8, false ; "keep everything")]
+    fn strategy_params_validate_tests(target_ratio: f64, keep_ratio: f64, short_share: f64, long_share: f64, ltv: f64, valid: bool) {
+        let params = StrategyParams { target_ratio, keep_ratio, short_share, long_share };
+        assert_eq!(params.validate(ltv).is_ok(), valid);
+    }
+
+    #[test]
+    fn initialize_bermuda_splits_by_params() {
+        let params = StrategyParams { target_ratio: 2.0, keep_ratio: 0.0, short_share: 0.5, long_share: 0.5 };
+        let short = Loan { collateral: 20.0, debt: 10.0 };
+        let long = Loan { collateral: 30.0, debt: 20.0 };
+        let equalize = initialize_bermuda(short, long, 2000.0, &params).unwrap();
+        // 20 eth invested, 10 in each leg at ratio 2: 20 of collateral for 10 of debt
+        assert_eq!(equalize.short_col_delta_usdc, 0.0);
+        assert_eq!(equalize.long_col_delta_eth, 10.0);
+        assert!(initialize_bermuda(Loan { collateral: 1.0, debt: 0.0 }, Loan { collateral: 1.0, debt: 0.0 }, 2000.0, &StrategyParams { target_ratio: 1.0, ..params }).is_err());
+    }
+
+    #[test]
+    fn rebalance_costs_tests() {
+        let equalize = Equalize {
+            eth_price: 2000.0,
+            flash_loan_currency: Currency::USDC,
+            flash_loan_value: 10000.0,
+            keep_usdc: 0.0,
+            short_col_delta_usdc: 6000.0,
+            short_debt_delta_eth: 2.0,
+            long_col_delta_eth: 3.0,
+            long_debt_delta_usdc: 4000.0,
+        };
+        let costs = rebalance_costs(&equalize, 0.0009, 0.01, 0.05).unwrap();
+        assert_eq!(costs.flash_loan_repay, 10009.0);
+        assert_eq!(costs.swap_currency, Currency::ETH);
+        assert_eq!(costs.swap_min_received, 10009.0);
+        assert_eq!((costs.swap_amount * 1e4).round(), 50551.0);
+        assert_eq!((costs.total_cost_eth * 1e6).round(), 105051.0);
+        assert!(rebalance_costs(&equalize, 0.0009, 1.0, 0.0).is_err());
+
+        // the kept $ are not swapped, the slippage is only paid on the flash loan repay
+        let eth_loan = Equalize {
+            flash_loan_currency: Currency::ETH,
+            flash_loan_value: 5.0,
+            keep_usdc: 1000.0,
+            short_col_delta_usdc: 7000.0,
+            long_debt_delta_usdc: 4000.0,
+            ..equalize
+        };
+        let costs = rebalance_costs(&eth_loan, 0.0009, 0.01, 0.05).unwrap();
+        assert_eq!(costs.swap_currency, Currency::USDC);
+        assert_eq!(costs.swap_min_received, costs.flash_loan_repay);
+        assert_eq!((costs.swap_amount * 100.0).round(), 1011010.0);
+        assert_eq!((costs.slippage_cost_eth * 1e6).round(), 50551.0);
+    }
+
+    #[test_case(1.0, (2550.0, 5667.0, 1600.0, 1000.0) ; "linear")]
+    #[test_case(EXP_FACTOR, (2550.0, 2985.0, 1600.0, 1532.0) ; "model exponent")]
+    fn liquidation_prices_tests(exp_factor: f64, expected: (f64, f64, f64, f64)) {
+        let short = Loan{collateral: 30.0, debt: 20.0};
+        let long = Loan{collateral: 10.0, debt: 6.0};
+        let prices = liquidation_prices(&short, 0.85, &long, 0.75, 2000.0, exp_factor).unwrap();
+        assert_eq!(prices.short_static.unwrap().round(), expected.0);
+        assert_eq!(prices.short_model.unwrap().round(), expected.1);
+        assert_eq!(prices.long_static.unwrap().round(), expected.2);
+        assert_eq!(prices.long_model.unwrap().round(), expected.3);
+
+        let no_debt = Loan{collateral: 10.0, debt: 0.0};
+        let prices = liquidation_prices(&no_debt, 0.85, &no_debt, 0.75, 2000.0, exp_factor).unwrap();
+        assert_eq!(prices.short_static, None);
+        assert_eq!(prices.long_model, None);
+
+        // no equity left: the static prices are still solved, the model ones are not
+        for underwater in [Loan{collateral: 10.0, debt: 10.0}, Loan{collateral: 8.0, debt: 10.0}].iter() {
+            let prices = liquidation_prices(underwater, 0.85, underwater, 0.75, 2000.0, exp_factor).unwrap();
+            assert!(prices.short_static.unwrap() > 0.0 && prices.long_static.unwrap() > 0.0);
+            assert_eq!((prices.short_model, prices.long_model), (None, None));
+        }
+    }
+
     #[test_case((1200.0, 100.0, 10.0), 1000.0, (1000.0, 161.0, 7.0) ; "down normal")]
     #[test_case((4000.0, 4.948, 45.471), 1000.0, (1000.0, 181.886, 4.948) ; "down must skip")]
     fn predict_down_tests(current: (f64,f64,f64), base_price:f64, expected: (f64,f64,f64)) {
         let current = Prediction{price: current.0, short: current.1, long: current.2};
-        let next = predict_down(&current, base_price).unwrap();
+        let next = predict_down(&current, base_price, EXP_FACTOR).unwrap();
         assert_eq!(next.price, expected.0);
         assert_eq!(next.short.round(), expected.1.round());
         assert_eq!(next.long.round(), expected.2.round());
@@ -0,0 +1,207 @@
+use super::blockchain::{BlockTag, BlockchainReader, TransactionReceipt};
+use super::decode::revert_reason;
+use super::error::{BermudaError, Result};
+use super::transaction::UnsignedTransaction;
+use async_trait::async_trait;
+use ethabi::{Address, Contract, Hash, Token, Uint};
+use rustc_hex::ToHex;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+/// The output of a mocked call, or its revert data.
+type MockAnswer = Result<Vec<u8>, Vec<u8>>;
+
+/// An in-memory node serving canned answers, to test the protocol wrappers without a live node.
+///
+/// `eth_call` answers are keyed by the contract address and the calldata, that is the
+/// function selector followed by the encoded arguments.
+#[derive(Default)]
+pub struct MockBlockchainReader {
+    block: BlockTag,
+    timestamp: u64,
+    gas_price: Uint,
+    chain_id: Option<u64>,
+    calls: HashMap<(Address, Vec<u8>), MockAnswer>,
+    balances: HashMap<Address, Uint>,
+    storage: HashMap<(Address, Uint), Vec<u8>>,
+    eth_call_count: Cell<usize>,
+    node_error: Option<BermudaError>,
+    impersonated: RefCell<HashSet<Address>>,
+    sent: RefCell<Vec<UnsignedTransaction>>,
+}
+
+impl MockBlockchainReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer every read as if made at `block`.
+    pub fn at_block(self, block: BlockTag) -> Self {
+        Self { block, ..self }
+    }
+
+    /// The timestamp of the block the reads are made at.
+    pub fn mock_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn mock_gas_price(&mut self, wei: Uint) {
+        self.gas_price = wei;
+    }
+
+    /// The chain id, mainnet unless mocked.
+    pub fn mock_chain_id(&mut self, chain_id: u64) {
+        self.chain_id = Some(chain_id);
+    }
+
+    /// Answer `outputs` when the function `name` of the contract is called with `params`.
+    pub fn mock_call(
+        &mut self,
+        contract: &Contract,
+        contract_address: &Address,
+        name: &str,
+        params: &[Token],
+        outputs: &[Token],
+    ) -> Result<()> {
+        let data = contract.function(name)?.encode_input(params)?;
+        self.mock_raw_call(contract_address, data, ethabi::encode(outputs));
+        Ok(())
+    }
+
+    /// Answer the raw `output` when the contract is called with the raw `data`.
+    pub fn mock_raw_call(&mut self, contract_address: &Address, data: Vec<u8>, output: Vec<u8>) {
+        self.calls.insert((*contract_address, data), Ok(output));
+    }
+
+    /// Revert with the raw `revert_data` when the contract is called with the raw `data`.
+    pub fn mock_revert(&mut self, contract_address: &Address, data: Vec<u8>, revert_data: Vec<u8>) {
+        self.calls.insert((*contract_address, data), Err(revert_data));
+    }
+
+    /// Fail every `eth_call` with `error`, like a node that cannot be reached.
+    pub fn mock_node_error(&mut self, error: BermudaError) {
+        self.node_error = Some(error);
+    }
+
+    /// How many `eth_call` were answered, or failed, so far.
+    pub fn eth_call_count(&self) -> usize {
+        self.eth_call_count.get()
+    }
+
+    pub fn mock_balance(&mut self, address: &Address, wei: Uint) {
+        self.balances.insert(*address, wei);
+    }
+
+    pub fn mock_storage(&mut self, address: &Address, position: Uint, word: Vec<u8>) {
+        self.storage.insert((*address, position), word);
+    }
+
+    /// The transactions sent so far. They are mined at once, their hash being their number.
+    pub fn sent_transactions(&self) -> Vec<UnsignedTransaction> {
+        self.sent.borrow().clone()
+    }
+
+    /// The accounts currently impersonated.
+    pub fn impersonated_accounts(&self) -> Vec<Address> {
+        self.impersonated.borrow().iter().cloned().collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockchainReader for MockBlockchainReader {
+    fn block(&self) -> BlockTag {
+        self.block
+    }
+
+    /// The number of a `BlockTag::Number` block, 0 for the others.
+    async fn block_number(&self) -> Result<u64> {
+        match self.block {
+            BlockTag::Number(number) => Ok(number),
+            _ => Ok(0),
+        }
+    }
+
+    async fn block_timestamp(&self) -> Result<u64> {
+        Ok(self.timestamp)
+    }
+
+    async fn eth_call(&self, contract_address: &Address, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.eth_call_count.set(self.eth_call_count.get() + 1);
+        if let Some(error) = &self.node_error {
+            return Err(error.clone());
+        }
+        match self.calls.get(&(*contract_address, data.clone())) {
+            Some(Ok(output)) => Ok(output.clone()),
+            Some(Err(revert_data)) => Err(BermudaError::Revert {
+                contract: *contract_address,
+                reason: revert_reason(revert_data),
+            }),
+            // like a node calling an address without code
+            None if !self.calls.keys().any(|(address, _)| address == contract_address) => Ok(Vec::new()),
+            None => Err(BermudaError::Rpc {
+                code: -32000,
+                message: format!(
+                    "no mocked eth_call to {:#x} with 0x{}",
+                    contract_address,
+                    data.to_hex::<String>()
+                ),
+            }),
+        }
+    }
+
+    async fn eth_get_balance(&self, address: &Address) -> Result<Uint> {
+        Ok(self.balances.get(address).cloned().unwrap_or_default())
+    }
+
+    async fn eth_get_storage_at(&self, address: &Address, position: Uint) -> Result<Vec<u8>> {
+        Ok(self
+            .storage
+            .get(&(*address, position))
+            .cloned()
+            .unwrap_or_else(|| vec![0; 32]))
+    }
+
+    async fn eth_gas_price(&self) -> Result<Uint> {
+        Ok(self.gas_price)
+    }
+
+    async fn eth_chain_id(&self) -> Result<u64> {
+        Ok(self.chain_id.unwrap_or(1))
+    }
+
+    /// Answered like `eth_call`, whoever the sender.
+    async fn eth_call_transaction(&self, transaction: &UnsignedTransaction) -> Result<Vec<u8>> {
+        self.eth_call(&transaction.to, transaction.data.clone()).await
+    }
+
+    /// Reverts like `eth_call`, and fails unless the sender is impersonated.
+    async fn eth_send_transaction(&self, transaction: &UnsignedTransaction) -> Result<Hash> {
+        if !self.impersonated.borrow().contains(&transaction.from) {
+            return Err(BermudaError::Rpc { code: -32000, message: String::from("unknown account") });
+        }
+        self.eth_call_transaction(transaction).await?;
+        self.sent.borrow_mut().push(transaction.clone());
+        Ok(Hash::from_low_u64_be(self.sent.borrow().len() as u64))
+    }
+
+    async fn eth_get_transaction_receipt(&self, hash: &Hash) -> Result<Option<TransactionReceipt>> {
+        let sent = self.sent.borrow();
+        let index = hash.to_low_u64_be() as usize;
+        Ok(sent.get(index.wrapping_sub(1)).map(|transaction| TransactionReceipt {
+            transaction_hash: *hash,
+            block_number: index as u64,
+            gas_used: transaction.gas.low_u64(),
+            success: true,
+        }))
+    }
+
+    async fn impersonate_account(&self, address: &Address) -> Result<()> {
+        self.impersonated.borrow_mut().insert(*address);
+        Ok(())
+    }
+
+    async fn stop_impersonating_account(&self, address: &Address) -> Result<()> {
+        self.impersonated.borrow_mut().remove(address);
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use super::error::{BermudaError, Result};
+use ethabi::{Address, Contract, Function, Token};
+
+/// Multicall3 is deployed at the same address on mainnet and most testnets.
+pub const MULTICALL3_ADDRESS: &str = "cA11bde05977b3631167028862bE2a173976CA11";
+
+struct BatchCall<'a> {
+    function: &'a Function,
+    address: Address,
+    data: Vec<u8>,
+}
+
+/// Contract calls collected to be read in a single round-trip.
+#[derive(Default)]
+pub struct Batch<'a> {
+    calls: Vec<BatchCall<'a>>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new() -> Self {
+        Self { calls: Vec::new() }
+    }
+
+    /// Queue a call and return its index in the results of the batch.
+    pub fn add(
+        &mut self,
+        contract: &'a Contract,
+        contract_address: &Address,
+        name: &str,
+        params: &[Token],
+    ) -> Result<usize> {
+        let function = contract.function(name)?;
+        let data = function.encode_input(params)?;
+        self.calls.push(BatchCall {
+            function,
+            address: *contract_address,
+            data,
+        });
+        Ok(self.calls.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// The target of the call at `index`.
+    pub(crate) fn address(&self, index: usize) -> &Address {
+        &self.calls[index].address
+    }
+
+    /// The target and the calldata of every queued call.
+    pub(crate) fn requests(&self) -> impl Iterator<Item = (&Address, &[u8])> {
+        self.calls.iter().map(|call| (&call.address, call.data.as_slice()))
+    }
+
+    pub(crate) fn decode_output(&self, index: usize, data: &[u8]) -> Result<Vec<Token>> {
+        Ok(self.calls[index].function.decode_output(data)?)
+    }
+
+    /// The `aggregate3` calldata, allowing every call to fail on its own.
+    pub(crate) fn encode_aggregate3(&self, multicall: &Contract) -> Result<Vec<u8>> {
+        let calls = self
+            .calls
+            .iter()
+            .map(|call| {
+                Token::Tuple(vec![
+                    Token::Address(call.address),
+                    Token::Bool(true),
+                    Token::Bytes(call.data.clone()),
+                ])
+            })
+            .collect();
+        Ok(multicall.function("aggregate3")?.encode_input(&[Token::Array(calls)])?)
+    }
+}
+
+/// The `(success, returnData)` of a call made through `aggregate3`.
+pub(crate) type CallResult = (bool, Vec<u8>);
+
+/// Split the output of `aggregate3` into the result of each call.
+pub(crate) fn decode_aggregate3(multicall: &Contract, data: &[u8]) -> Result<Vec<CallResult>> {
+    let tokens = multicall.function("aggregate3")?.decode_output(data)?;
+    let results = match tokens.into_iter().next() {
+        Some(Token::Array(results)) => results,
+        _ => return Err(BermudaError::Abi(String::from("unexpected aggregate3 output"))),
+    };
+    results
+        .into_iter()
+        .map(|result| match result {
+            Token::Tuple(fields) => match fields.as_slice() {
+                [Token::Bool(success), Token::Bytes(data)] => Ok((*success, data.clone())),
+                _ => Err(BermudaError::Abi(String::from("unexpected aggregate3 result"))),
+            },
+            _ => Err(BermudaError::Abi(String::from("unexpected aggregate3 result"))),
+        })
+        .collect()
+}
+
+pub(crate) fn load_multicall3() -> Result<Contract> {
+    let multicall_abi: &[u8] = include_bytes!("abi/multicall3.abi");
+    Ok(Contract::load(multicall_abi)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethabi::Uint;
+
+    #[test]
+    fn aggregate3_round_trip() {
+        let erc20: Contract = Contract::load(&include_bytes!("abi/erc20.abi")[..]).unwrap();
+        let multicall = load_multicall3().unwrap();
+        let token: Address = "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap();
+        let mut batch = Batch::new();
+        let decimals = batch.add(&erc20, &token, "decimals", &[]).unwrap();
+        let symbol = batch.add(&erc20, &token, "symbol", &[]).unwrap();
+        assert_eq!(batch.len(), 2);
+
+        let input = multicall
+            .function("aggregate3")
+            .unwrap()
+            .decode_input(&batch.encode_aggregate3(&multicall).unwrap()[4..])
+            .unwrap();
+        assert_eq!(
+            input,
+            vec![Token::Array(vec![
+                Token::Tuple(vec![
+                    Token::Address(token),
+                    Token::Bool(true),
+                    Token::Bytes(erc20.function("decimals").unwrap().short_signature().to_vec()),
+                ]),
+                Token::Tuple(vec![
+                    Token::Address(token),
+                    Token::Bool(true),
+                    Token::Bytes(erc20.function("symbol").unwrap().short_signature().to_vec()),
+                ]),
+            ])]
+        );
+
+        let output = ethabi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Bytes(ethabi::encode(&[Token::Uint(Uint::from(18))])),
+            ]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+        let results = decode_aggregate3(&multicall, &output).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[decimals].0);
+        assert!(!results[symbol].0);
+        assert_eq!(
+            batch.decode_output(decimals, &results[decimals].1).unwrap(),
+            vec![Token::Uint(Uint::from(18))]
+        );
+    }
+}
@@ -0,0 +1,456 @@
+use super::amount::Amount;
+use super::aave::{Aave, AaveAccount};
+use super::blockchain::BlockchainReader;
+use super::chainlink::{Chainlink, OracleLimits, PriceReading};
+use super::compound::{CometAccount, Compound};
+use super::decode::address;
+use super::config::{Config, Contracts, CostConfig, Profile, StrategyConfig};
+use super::error::Result;
+use super::multicall::Batch;
+use super::smart_wallet::SmartWallet;
+use super::watchlist::{get_holdings, Holding, WatchedToken};
+use super::{initialize_bermuda, liquidation_prices, predict, rebalance_costs};
+use super::{Equalize, LiquidationPrices, Prediction, RebalanceCosts, StrategyParams};
+use super::{REBALANCE_DOWN_RATIO, REBALANCE_UP_RATIO};
+use ethabi::Address;
+use serde::Serialize;
+
+/// Everything `Portfolio::load` needs besides the reader and the smart wallet.
+#[derive(Debug, Clone)]
+pub struct PortfolioConfig {
+    pub contracts: Contracts,
+    /// The tokens of the owner wallet counted in the total.
+    pub tokens: Vec<WatchedToken>,
+    pub strategy: StrategyConfig,
+    pub oracle: OracleLimits,
+    /// The limits of the feeds pricing the tokens.
+    pub feed_oracle: OracleLimits,
+    pub costs: CostConfig,
+}
+
+impl PortfolioConfig {
+    /// The settings of `profile`, with the contracts of its network and the defaults of
+    /// everything it leaves out.
+    pub fn from_profile(config: &Config, profile: &Profile) -> Result<Self> {
+        Ok(Self {
+            contracts: config.contracts(profile)?,
+            tokens: profile.watched_tokens()?.unwrap_or_else(WatchedToken::defaults),
+            strategy: profile.strategy,
+            oracle: profile.oracle.unwrap_or_else(OracleLimits::eth_usd),
+            feed_oracle: profile.feed_oracle.unwrap_or_else(OracleLimits::daily),
+            costs: profile.costs,
+        })
+    }
+}
+
+/// A bermuda read from the chain at the block of the reader.
+#[derive(Serialize, Debug)]
+pub struct Portfolio {
+    /// The owner of the smart wallet, whose wallet holds the eth and the watched tokens.
+    pub owner: Address,
+    pub eth_reading: PriceReading,
+    pub params: StrategyParams,
+    /// The exponent the predictions are made with.
+    pub exp_factor: f64,
+    pub snapshot: PortfolioSnapshot,
+}
+
+impl Portfolio {
+    /// Read both legs of the smart wallet, the wallet of its owner and the ETH / USD price, plan
+    /// the next rebalance with its costs and predict the portfolio over `prediction_grid`.
+    pub async fn load<R: BlockchainReader>(reader: &R, smart_wallet: &str, config: &PortfolioConfig) -> Result<Portfolio> {
+        let contracts = &config.contracts;
+        let smart_wallet = smart_wallet.strip_prefix("0x").unwrap_or(smart_wallet);
+        let aave = Aave::at(reader, contracts.aave)?;
+        let compound = Compound::at(reader, contracts.compound, contracts.weth)?;
+        let chainlink = Chainlink::at(reader, contracts.chainlink)?.with_limits(config.oracle);
+        let eth_reading = chainlink.get_eth_price().await?;
+        let price = eth_reading.answer;
+
+        let wallet = SmartWallet::new(reader, smart_wallet)?;
+        let mut batch = Batch::new();
+        let owner_call = wallet.add_owner(&mut batch)?;
+        let account_call = aave.add_account(&mut batch, smart_wallet)?;
+        let premium_call = aave.add_flash_loan_premium(&mut batch)?;
+        let results = reader.call_batch(&batch).await?;
+        let result = |index: usize| results[index].clone();
+        let owner = address(&result(owner_call)?, 0)?;
+        let short_account = aave.decode_account(&result(account_call)?)?;
+        let short_loan = short_account.loan();
+        let short = short_loan.collateral - short_loan.debt;
+        let long_account = compound.get_account(smart_wallet).await?;
+        let long_loan = long_account.loan_at(price);
+        let long = long_loan.collateral - long_loan.debt;
+
+        let params = config.strategy.params();
+        // an empty aave account reports a zero ltv
+        let ltv = [short_account.ltv, long_account.borrow_collateral_factor]
+            .iter()
+            .filter(|ltv| **ltv > 0.0)
+            .fold(1.0, |min: f64, ltv| min.min(*ltv));
+        params.validate(ltv)?;
+        let equalize = initialize_bermuda(short_loan, long_loan, price, &params)?;
+        let gas_price = reader.eth_gas_price().await?;
+        let gas_cost_eth = Amount::from_wei(gas_price.saturating_mul(config.costs.gas_limit.into())).to_f64();
+        let costs = rebalance_costs(&equalize, aave.decode_flash_loan_premium(&result(premium_call)?)?, config.costs.slippage, gas_cost_eth)?;
+
+        let wallet_eth = reader.get_eth_balance(&owner).await?.to_f64();
+        let holdings = get_holdings(reader, &owner, &config.tokens, price, config.feed_oracle).await?;
+        let tokens_eth: f64 = holdings.iter().map(|holding| holding.eth_value).sum();
+
+        let exp_factor = config.strategy.exponent()?;
+        let predictions = prediction_grid(&Prediction { price, short, long }, exp_factor)?;
+        let (rebalance_down, rebalance_up) = rebalance_prices(&predictions);
+        let liquidation = liquidation_prices(
+            &short_account.loan(),
+            short_account.liquidation_threshold,
+            &long_account.loan(),
+            long_account.liquidate_collateral_factor,
+            price,
+            exp_factor,
+        )?;
+
+        Ok(Portfolio {
+            owner,
+            eth_reading,
+            params,
+            exp_factor,
+            snapshot: PortfolioSnapshot {
+                block: reader.block_number().await?,
+                timestamp: reader.block_timestamp().await?,
+                eth_price: price,
+                wallet_eth,
+                holdings,
+                tokens_eth,
+                short,
+                short_account,
+                long,
+                long_account,
+                total: wallet_eth + short + long + tokens_eth,
+                equalize,
+                costs,
+                rebalance_down,
+                rebalance_up,
+                liquidation,
+                predictions,
+            },
+        })
+    }
+
+    /// The legs as `predict` starts from.
+    pub fn current(&self) -> Prediction {
+        Prediction {
+            price: self.snapshot.eth_price,
+            short: self.snapshot.short,
+            long: self.snapshot.long,
+        }
+    }
+}
+
+/// `current` predicted from 500 $ to 20 000 $, with steps growing with the price.
+pub fn prediction_grid(current: &Prediction, exp_factor: f64) -> tera::Result<Vec<Prediction>> {
+    let prices = (500..1000)
+        .step_by(50)
+        .chain((1000..3000).step_by(100))
+        .chain((3000..5000).step_by(250))
+        .chain((5000..10000).step_by(500))
+        .chain((10000..=20000).step_by(1000));
+    prices.map(|price| predict(current, price as f64, exp_factor)).collect()
+}
+
+/// The highest price of `predictions` at which the short leg outgrows the long one enough to
+/// rebalance, and the lowest at which the long leg does, 0 when there is none.
+pub fn rebalance_prices(predictions: &[Prediction]) -> (f64, f64) {
+    let down = predictions
+        .iter()
+        .rev()
+        .find(|p| p.long * REBALANCE_DOWN_RATIO <= p.short)
+        .map_or(0.0, |prediction| prediction.price);
+    let up = predictions
+        .iter()
+        .find(|p| p.short * REBALANCE_UP_RATIO <= p.long)
+        .map_or(0.0, |prediction| prediction.price);
+    (down, up)
+}
+
+/// Everything `show` reports about the portfolio at one block. Values are in eth unless stated otherwise.
+#[derive(Serialize, Debug)]
+pub struct PortfolioSnapshot {
+    pub block: u64,
+    /// Unix timestamp of the block.
+    pub timestamp: u64,
+    /// In $.
+    pub eth_price: f64,
+    pub wallet_eth: f64,
+    /// The watched tokens of the owner wallet, its stablecoins by default.
+    pub holdings: Vec<Holding>,
+    pub tokens_eth: f64,
+    pub short: f64,
+    pub short_account: AaveAccount,
+    pub long: f64,
+    pub long_account: CometAccount,
+    pub total: f64,
+    pub equalize: Equalize,
+    pub costs: RebalanceCosts,
+    /// In $, 0 when the rebalance is not within the predicted prices.
+    pub rebalance_down: f64,
+    pub rebalance_up: f64,
+    pub liquidation: LiquidationPrices,
+    pub predictions: Vec<Prediction>,
+}
+
+impl PortfolioSnapshot {
+    /// The total once the rebalance is paid for.
+    pub fn net_total(&self) -> f64 {
+        self.total - self.costs.total_cost_eth
+    }
+
+    /// A CSV header and a single line with the scalar values of the snapshot and the planned
+    /// rebalance, then after an empty line the predictions with their own header. The
+    /// holdings are only in the JSON format.
+    pub fn to_csv(&self) -> String {
+        let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+        let values = [
+            self.block.to_string(),
+            self.eth_price.to_string(),
+            self.wallet_eth.to_string(),
+            self.tokens_eth.to_string(),
+            self.short.to_string(),
+            self.long.to_string(),
+            self.total.to_string(),
+            self.net_total().to_string(),
+            format!("{:?}", self.equalize.flash_loan_currency),
+            self.equalize.flash_loan_value.to_string(),
+            self.equalize.keep_usdc.to_string(),
+            self.equalize.short_col_delta_usdc.to_string(),
+            self.equalize.short_debt_delta_eth.to_string(),
+            self.equalize.long_col_delta_eth.to_string(),
+            self.equalize.long_debt_delta_usdc.to_string(),
+            self.costs.total_cost_eth.to_string(),
+            self.rebalance_down.to_string(),
+            self.rebalance_up.to_string(),
+            optional(self.liquidation.short_static),
+            optional(self.liquidation.short_model),
+            optional(self.liquidation.long_static),
+            optional(self.liquidation.long_model),
+        ];
+        let mut csv = format!(
+            "block,eth_price,wallet_eth,tokens_eth,short,long,total,net_total,flash_loan_currency,flash_loan_value,keep_usdc,short_col_delta_usdc,short_debt_delta_eth,long_col_delta_eth,long_debt_delta_usdc,rebalance_cost_eth,rebalance_down,rebalance_up,short_liquidation,short_liquidation_model,long_liquidation,long_liquidation_model\n{}\n",
+            values.join(",")
+        );
+        csv.push_str("\nprice,short,long,total\n");
+        for prediction in self.predictions.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                prediction.price,
+                prediction.short,
+                prediction.long,
+                prediction.short + prediction.long
+            ));
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockTag;
+    use crate::{BermudaError, Currency, Loan, MockBlockchainReader};
+    use ethabi::{Contract, Token, Uint};
+    use test_case::test_case;
+
+    const SMART_WALLET: &str = "0000000000000000000000000000000000005a1e";
+    const OWNER: &str = "00000000000000000000000000000000000a11ce";
+    const BLOCK_TIMESTAMP: u64 = 1_700_000_000;
+
+    fn raw(value: f64, decimals: u8) -> Uint {
+        Amount::parse(&format!("{:.*}", decimals as usize, value), decimals).unwrap().raw()
+    }
+
+    /// A node at block 16000000 serving a bermuda with `short` and `long` in eth, eth at
+    /// 2000 $ answered `age` seconds before the block, and an owner wallet holding 1 eth.
+    fn mock_node(short: &Loan, long: &Loan, age: u64) -> (MockBlockchainReader, PortfolioConfig) {
+        let contracts = Contracts::mainnet();
+        let smart_wallet: Address = SMART_WALLET.parse().unwrap();
+        let owner: Address = OWNER.parse().unwrap();
+        let load = |abi: &[u8]| Contract::load(abi).unwrap();
+        let mut reader = MockBlockchainReader::new().at_block(BlockTag::Number(16_000_000));
+        reader.mock_timestamp(BLOCK_TIMESTAMP);
+        reader.mock_gas_price(Uint::exp10(9) * 20);
+        reader.mock_balance(&owner, Uint::exp10(18));
+
+        let chainlink = load(include_bytes!("abi/chainlink.abi"));
+        let updated_at = Token::Uint(Uint::from(BLOCK_TIMESTAMP - age));
+        let round = [Token::Uint(Uint::one()), Token::Int(raw(2000.0, 8)), updated_at.clone(), updated_at, Token::Uint(Uint::one())];
+        reader.mock_call(&chainlink, &contracts.chainlink, "latestRoundData", &[], &round).unwrap();
+        reader.mock_call(&chainlink, &contracts.chainlink, "decimals", &[], &[Token::Uint(Uint::from(8))]).unwrap();
+
+        let wallet = load(include_bytes!("abi/smart_wallet.abi"));
+        reader.mock_call(&wallet, &smart_wallet, "owner", &[], &[Token::Address(owner)]).unwrap();
+
+        let aave = load(include_bytes!("abi/aave.abi"));
+        let health_factor = match short.debt > 0.0 {
+            true => raw(short.collateral * 0.85 / short.debt, 18),
+            false => Uint::MAX,
+        };
+        let account = [
+            Token::Uint(raw(short.collateral, 18)),
+            Token::Uint(raw(short.debt, 18)),
+            Token::Uint(raw((short.collateral * 0.8 - short.debt).max(0.0), 18)),
+            Token::Uint(Uint::from(8500)),
+            Token::Uint(Uint::from(8000)),
+            Token::Uint(health_factor),
+        ];
+        reader.mock_call(&aave, &contracts.aave, "getUserAccountData", &[Token::Address(smart_wallet)], &account).unwrap();
+        reader.mock_call(&aave, &contracts.aave, "FLASHLOAN_PREMIUM_TOTAL", &[], &[Token::Uint(Uint::from(9))]).unwrap();
+
+        let comet = load(include_bytes!("abi/cUSDCv3.abi"));
+        let eth_feed = Address::from_low_u64_be(0xe7f);
+        let usdc_feed = Address::from_low_u64_be(0x5dc);
+        let factor = |percent: u64| Token::Uint(Uint::exp10(16) * percent);
+        let mut mock = |name: &str, params: &[Token], outputs: &[Token]| {
+            reader.mock_call(&comet, &contracts.compound, name, params, outputs).unwrap();
+        };
+        mock(
+            "userCollateral",
+            &[Token::Address(smart_wallet), Token::Address(contracts.weth)],
+            &[Token::Uint(raw(long.collateral, 18)), Token::Uint(Uint::zero())],
+        );
+        mock("borrowBalanceOf", &[Token::Address(smart_wallet)], &[Token::Uint(raw(long.debt * 2000.0, 6))]);
+        mock(
+            "getAssetInfoByAddress",
+            &[Token::Address(contracts.weth)],
+            &[Token::Tuple(vec![
+                Token::Uint(Uint::from(2)),
+                Token::Address(contracts.weth),
+                Token::Address(eth_feed),
+                Token::Uint(Uint::exp10(18)),
+                factor(83),
+                factor(90),
+                factor(95),
+                Token::Uint(Uint::exp10(24)),
+            ])],
+        );
+        mock("baseTokenPriceFeed", &[], &[Token::Address(usdc_feed)]);
+        mock("isLiquidatable", &[Token::Address(smart_wallet)], &[Token::Bool(false)]);
+        mock("isBorrowCollateralized", &[Token::Address(smart_wallet)], &[Token::Bool(true)]);
+        // Comet prices eth 1% above chainlink, which the legs must not use
+        mock("getPrice", &[Token::Address(eth_feed)], &[Token::Uint(raw(2020.0, 8))]);
+        mock("getPrice", &[Token::Address(usdc_feed)], &[Token::Uint(Uint::exp10(8))]);
+
+        let config = PortfolioConfig {
+            contracts,
+            tokens: Vec::new(),
+            strategy: StrategyConfig::default(),
+            oracle: OracleLimits::eth_usd(),
+            feed_oracle: OracleLimits::daily(),
+            costs: CostConfig::default(),
+        };
+        (reader, config)
+    }
+
+    #[test]
+    fn portfolio_snapshot_csv() {
+        let equalize = initialize_bermuda(
+            Loan { collateral: 6.0, debt: 4.0 },
+            Loan { collateral: 3.0, debt: 2.0 },
+            2000.0,
+            &StrategyParams::default(),
+        )
+        .unwrap();
+        let eth = |value: f64| Amount::new(raw(value, 18), 18);
+        let snapshot = PortfolioSnapshot {
+            block: 16_000_000,
+            timestamp: BLOCK_TIMESTAMP,
+            eth_price: 2000.0,
+            wallet_eth: 1.0,
+            holdings: Vec::new(),
+            tokens_eth: 0.0,
+            short: 2.0,
+            short_account: AaveAccount {
+                total_collateral: eth(6.0),
+                total_debt: eth(4.0),
+                available_borrows: eth(0.8),
+                liquidation_threshold: 0.85,
+                ltv: 0.8,
+                health_factor: Some(1.275),
+            },
+            long: 1.0,
+            long_account: CometAccount {
+                collateral: eth(3.0),
+                debt: Amount::new(raw(4000.0, 6), 6),
+                collateral_price: 2000.0,
+                base_price: 1.0,
+                borrow_collateral_factor: 0.83,
+                liquidate_collateral_factor: 0.9,
+                liquidation_factor: 0.95,
+                is_liquidatable: false,
+                is_borrow_collateralized: true,
+            },
+            total: 4.0,
+            costs: rebalance_costs(&equalize, 0.0009, 0.005, 0.01).unwrap(),
+            equalize,
+            rebalance_down: 800.0,
+            rebalance_up: 4000.0,
+            liquidation: LiquidationPrices { short_static: Some(2550.0), short_model: None, long_static: None, long_model: None },
+            predictions: prediction_grid(&Prediction { price: 2000.0, short: 2.0, long: 1.0 }, 2.6).unwrap(),
+        };
+        let csv = snapshot.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4 + snapshot.predictions.len());
+        assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+        assert!(lines[1].starts_with("16000000,2000,1,0,2,1,4,"));
+        assert!(lines[1].contains(&format!(",{},", snapshot.equalize.long_debt_delta_usdc)));
+        assert!(lines[1].ends_with(",800,4000,2550,,,"));
+        assert_eq!((lines[2], lines[3]), ("", "price,short,long,total"));
+        assert!(lines[4].starts_with("500,"));
+        assert_eq!(lines.last().unwrap().split(',').count(), 4);
+    }
+
+    #[test_case(Loan { collateral: 12.0, debt: 8.0 }, Loan { collateral: 3.0, debt: 2.0 } => (4.0, 1.0, Currency::ETH) ; "short outgrew the long leg")]
+    #[test_case(Loan { collateral: 3.0, debt: 2.0 }, Loan { collateral: 12.0, debt: 8.0 } => (1.0, 4.0, Currency::USDC) ; "long outgrew the short leg")]
+    #[test_case(Loan { collateral: 0.0, debt: 0.0 }, Loan { collateral: 3.0, debt: 2.0 } => (0.0, 1.0, Currency::USDC) ; "empty aave account")]
+    #[tokio::test]
+    async fn load_tests(short: Loan, long: Loan) -> (f64, f64, Currency) {
+        let (reader, config) = mock_node(&short, &long, 60);
+        let portfolio = Portfolio::load(&reader, SMART_WALLET, &config).await.unwrap();
+        assert_eq!(portfolio.owner, OWNER.parse().unwrap());
+        assert_eq!(portfolio.eth_reading.age, 60);
+        let snapshot = &portfolio.snapshot;
+        assert_eq!((snapshot.block, snapshot.timestamp), (16_000_000, BLOCK_TIMESTAMP));
+        assert_eq!(snapshot.eth_price, 2000.0);
+        assert_eq!(snapshot.total, 1.0 + snapshot.short + snapshot.long);
+        assert_eq!(snapshot.short_account.health_factor.is_some(), short.debt > 0.0);
+        assert_eq!(snapshot.costs.flash_loan_premium, 0.0009);
+        // 20 gwei for the default gas limit
+        assert_eq!(snapshot.costs.gas_cost_eth, 20e-9 * config.costs.gas_limit as f64);
+        assert_eq!(snapshot.predictions.len(), 59);
+        (snapshot.short, snapshot.long, snapshot.equalize.flash_loan_currency)
+    }
+
+    #[tokio::test]
+    async fn load_errors() {
+        let (short, long) = (Loan { collateral: 12.0, debt: 8.0 }, Loan { collateral: 3.0, debt: 2.0 });
+        // the legs cannot be borrowed at the target ratio
+        let (reader, config) = mock_node(&short, &long, 60);
+        let config = PortfolioConfig { strategy: StrategyConfig { target_ratio: 1.1, ..config.strategy }, ..config };
+        assert!(matches!(Portfolio::load(&reader, SMART_WALLET, &config).await, Err(BermudaError::Model(_))));
+
+        let (reader, config) = mock_node(&short, &long, 4 * 3600);
+        assert!(matches!(Portfolio::load(&reader, SMART_WALLET, &config).await, Err(BermudaError::StaleOracle { .. })));
+    }
+
+    #[test]
+    fn prediction_grid_and_rebalance_prices() {
+        let current = Prediction { price: 2000.0, short: 10.0, long: 5.0 };
+        let predictions = prediction_grid(&current, 2.6).unwrap();
+        assert_eq!(predictions.len(), 59);
+        assert_eq!(predictions[0].price, 500.0);
+        assert_eq!(predictions.last().unwrap().price, 20000.0);
+
+        let (down, up) = rebalance_prices(&predictions);
+        assert!(down < current.price && current.price < up);
+        assert_eq!(rebalance_prices(&predictions[20..21]), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,80 @@
+use super::blockchain::{BlockchainReader, TransactionReceipt};
+use super::error::{BermudaError, Result};
+use super::transaction::UnsignedTransaction;
+use std::time::Duration;
+
+/// How many times the receipt of a sent transaction is asked for, `RECEIPT_POLL_DELAY` apart.
+const RECEIPT_POLLS: u32 = 20;
+const RECEIPT_POLL_DELAY: Duration = Duration::from_millis(500);
+
+/// Dry-runs transactions before they are signed, sending them for real from an impersonated
+/// sender on a local anvil or hardhat fork read by the reader.
+pub struct Simulator<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
+}
+
+impl<'a, R: BlockchainReader> Simulator<'a, R> {
+    pub fn new(blockchain_reader: &'a R) -> Self {
+        Self { blockchain_reader }
+    }
+
+    /// `eth_call` the transaction from its sender at the block of the reader, failing with
+    /// the decoded revert when it does not go through.
+    pub async fn call(&self, transaction: &UnsignedTransaction) -> Result<()> {
+        self.blockchain_reader.eth_call_transaction(transaction).await?;
+        Ok(())
+    }
+
+    /// Send the transaction, impersonating its sender, and wait for it to be mined.
+    pub async fn send(&self, transaction: &UnsignedTransaction) -> Result<TransactionReceipt> {
+        self.blockchain_reader.impersonate_account(&transaction.from).await?;
+        let sent = self.blockchain_reader.eth_send_transaction(transaction).await;
+        self.blockchain_reader.stop_impersonating_account(&transaction.from).await?;
+        let hash = sent?;
+
+        for _ in 0..RECEIPT_POLLS {
+            match self.blockchain_reader.eth_get_transaction_receipt(&hash).await? {
+                Some(receipt) if receipt.success => return Ok(receipt),
+                Some(_) => return Err(BermudaError::Revert { contract: transaction.to, reason: None }),
+                None => tokio::time::delay_for(RECEIPT_POLL_DELAY).await,
+            }
+        }
+        Err(BermudaError::Transport(format!("transaction {:#x} was not mined, is automine off?", hash)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+    use ethabi::{Address, Uint};
+
+    #[tokio::test]
+    async fn send_impersonates_the_sender() {
+        let contract = Address::from_low_u64_be(7);
+        let transaction = UnsignedTransaction {
+            from: Address::from_low_u64_be(8),
+            to: contract,
+            value: Uint::zero(),
+            data: vec![0x1c, 0xff, 0x79, 0xcd],
+            gas: Uint::from(21_000),
+            chain_id: Uint::one(),
+        };
+        let mut reader = MockBlockchainReader::new();
+        reader.mock_raw_call(&contract, transaction.data.clone(), Vec::new());
+        let simulator = Simulator::new(&reader);
+        simulator.call(&transaction).await.unwrap();
+        let receipt = simulator.send(&transaction).await.unwrap();
+        assert_eq!((receipt.block_number, receipt.gas_used), (1, 21_000));
+        assert_eq!(reader.sent_transactions(), vec![transaction.clone()]);
+        assert!(reader.impersonated_accounts().is_empty());
+
+        // a reverting transaction is not sent, and the sender is not impersonated anymore
+        let reverting = UnsignedTransaction { data: vec![0xde, 0xad], ..transaction };
+        reader.mock_revert(&contract, reverting.data.clone(), Vec::new());
+        let simulator = Simulator::new(&reader);
+        assert!(matches!(simulator.send(&reverting).await, Err(BermudaError::Revert { .. })));
+        assert_eq!(reader.sent_transactions().len(), 1);
+        assert!(reader.impersonated_accounts().is_empty());
+    }
+}
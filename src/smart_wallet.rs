@@ -1,15 +1,17 @@
-use super::blockchain::HttpBlockchainReader;
-use ethabi::{Address, Contract};
-use std::error::Error;
+use super::blockchain::BlockchainReader;
+use super::decode::{address, parse_address};
+use super::error::{BermudaError, Result};
+use super::multicall::Batch;
+use ethabi::{Address, Contract, ParamType, Token};
 
-pub struct SmartWallet<'a> {
-    blockchain_reader: &'a HttpBlockchainReader,
+pub struct SmartWallet<'a, R: BlockchainReader> {
+    blockchain_reader: &'a R,
     address: Address,
     contract: Contract,
 }
-impl<'a> SmartWallet<'a> {
-    pub fn new(blockchain_reader: &'a HttpBlockchainReader , smart_wallet_address:&str) -> Result<Self, Box<dyn Error>> {
-        let address: Address = smart_wallet_address.parse()?;
+impl<'a, R: BlockchainReader> SmartWallet<'a, R> {
+    pub fn new(blockchain_reader: &'a R, smart_wallet_address:&str) -> Result<Self> {
+        let address: Address = parse_address(smart_wallet_address)?;
         let smart_wallet_abi: &[u8] = include_bytes!("abi/smart_wallet.abi");
         let contract: Contract = Contract::load(smart_wallet_abi)?;
         Ok(Self {
@@ -18,7 +20,22 @@ impl<'a> SmartWallet<'a> {
             contract,
         })
     }
-    pub async fn get_owner(&self) -> Result<Address, Box<dyn Error>> {
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// The calldata of `execute(address,bytes)`, making the smart wallet delegatecall `target`.
+    pub fn encode_execute(&self, target: &Address, data: Vec<u8>) -> Result<Vec<u8>> {
+        let function = self
+            .contract
+            .functions_by_name("execute")?
+            .iter()
+            .find(|function| function.inputs.first().map(|input| &input.kind) == Some(&ParamType::Address))
+            .ok_or_else(|| BermudaError::Abi(String::from("no execute(address,bytes) in the smart wallet ABI")))?;
+        Ok(function.encode_input(&[Token::Address(*target), Token::Bytes(data)])?)
+    }
+
+    pub async fn get_owner(&self) -> Result<Address> {
         let tokens = self
             .blockchain_reader
             .call_function(
@@ -29,9 +46,13 @@ impl<'a> SmartWallet<'a> {
             )
             .await?;
 
-        let owner = tokens[0].clone().into_address();
-        let owner = owner.unwrap();
+        let owner = address(&tokens, 0)?;
 
         Ok(owner)
     }
+
+    /// Queue `owner` in `batch`, its output is the address of the owner.
+    pub fn add_owner<'b>(&'b self, batch: &mut Batch<'b>) -> Result<usize> {
+        batch.add(&self.contract, &self.address, "owner", &[])
+    }
 }
@@ -0,0 +1,349 @@
+use super::error::{BermudaError, Result};
+use super::portfolio::Portfolio;
+use super::Equalize;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    smart_wallet TEXT NOT NULL,
+    block INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    eth_price REAL NOT NULL,
+    wallet_eth REAL NOT NULL,
+    tokens_eth REAL NOT NULL,
+    short REAL NOT NULL,
+    long REAL NOT NULL,
+    total REAL NOT NULL,
+    equalize TEXT NOT NULL,
+    PRIMARY KEY (smart_wallet, block)
+);
+CREATE INDEX IF NOT EXISTS snapshots_timestamp ON snapshots (timestamp);
+CREATE TABLE IF NOT EXISTS holdings (
+    smart_wallet TEXT NOT NULL,
+    block INTEGER NOT NULL,
+    token TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    balance REAL NOT NULL,
+    usd_value REAL NOT NULL,
+    eth_value REAL NOT NULL,
+    PRIMARY KEY (smart_wallet, block, token)
+);
+";
+
+/// The balance of a watched token in the owner wallet when the snapshot was taken.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoredHolding {
+    /// The token address, lowercase without the 0x prefix.
+    pub token: String,
+    pub symbol: String,
+    pub balance: f64,
+    pub usd_value: f64,
+    pub eth_value: f64,
+}
+
+/// One recorded portfolio. Values are in eth unless stated otherwise.
+#[derive(Serialize, Debug)]
+pub struct StoredSnapshot {
+    /// Lowercase, without the 0x prefix.
+    pub smart_wallet: String,
+    pub block: u64,
+    /// Unix timestamp of the block.
+    pub timestamp: u64,
+    /// In $.
+    pub eth_price: f64,
+    pub wallet_eth: f64,
+    pub tokens_eth: f64,
+    /// The value of both legs at `eth_price`, as they add up to `total`.
+    pub short: f64,
+    pub long: f64,
+    pub total: f64,
+    /// The rebalance planned at that block.
+    pub equalize: Equalize,
+    /// The watched tokens making up `tokens_eth`.
+    pub holdings: Vec<StoredHolding>,
+}
+
+impl StoredSnapshot {
+    pub fn from_portfolio(portfolio: &Portfolio, smart_wallet: &str) -> Self {
+        let snapshot = &portfolio.snapshot;
+        Self {
+            smart_wallet: smart_wallet.trim_start_matches("0x").to_lowercase(),
+            block: snapshot.block,
+            timestamp: snapshot.timestamp,
+            eth_price: snapshot.eth_price,
+            wallet_eth: snapshot.wallet_eth,
+            tokens_eth: snapshot.tokens_eth,
+            short: snapshot.short,
+            long: snapshot.long,
+            total: snapshot.total,
+            equalize: snapshot.equalize.clone(),
+            holdings: snapshot
+                .holdings
+                .iter()
+                .map(|holding| StoredHolding {
+                    token: format!("{:x}", holding.token.address),
+                    symbol: holding.token.symbol.clone(),
+                    balance: holding.balance.to_f64(),
+                    usd_value: holding.usd_value,
+                    eth_value: holding.eth_value,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn csv_header() -> &'static str {
+        "smart_wallet,block,timestamp,eth_price,wallet_eth,tokens_eth,short,long,total,flash_loan_currency,flash_loan_value,keep_usdc,short_col_delta_usdc,short_debt_delta_eth,long_col_delta_eth,long_debt_delta_usdc\n"
+    }
+
+    /// The snapshot as a line of `csv_header`.
+    pub fn to_csv(&self) -> String {
+        let values = [
+            self.smart_wallet.clone(),
+            self.block.to_string(),
+            self.timestamp.to_string(),
+            self.eth_price.to_string(),
+            self.wallet_eth.to_string(),
+            self.tokens_eth.to_string(),
+            self.short.to_string(),
+            self.long.to_string(),
+            self.total.to_string(),
+            format!("{:?}", self.equalize.flash_loan_currency),
+            self.equalize.flash_loan_value.to_string(),
+            self.equalize.keep_usdc.to_string(),
+            self.equalize.short_col_delta_usdc.to_string(),
+            self.equalize.short_debt_delta_eth.to_string(),
+            self.equalize.long_col_delta_eth.to_string(),
+            self.equalize.long_debt_delta_usdc.to_string(),
+        ];
+        format!("{}\n", values.join(","))
+    }
+
+    /// The snapshot of a `snapshots` row, its holdings being read separately.
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let equalize: String = row.get(9)?;
+        Ok(Self {
+            smart_wallet: row.get(0)?,
+            block: row.get::<_, i64>(1)? as u64,
+            timestamp: row.get::<_, i64>(2)? as u64,
+            eth_price: row.get(3)?,
+            wallet_eth: row.get(4)?,
+            tokens_eth: row.get(5)?,
+            short: row.get(6)?,
+            long: row.get(7)?,
+            total: row.get(8)?,
+            equalize: serde_json::from_str(&equalize)
+                .map_err(|error| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, Box::new(error)))?,
+            holdings: Vec::new(),
+        })
+    }
+}
+
+/// The snapshots read by `show`, `html`, `watch` and the rebalance commands, kept in a
+/// SQLite file. A block is only recorded once per smart wallet.
+pub struct SnapshotStore {
+    connection: Connection,
+}
+
+impl SnapshotStore {
+    /// The store at `path`, created with its directory if it does not exist yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| store_error(path, error))?;
+        }
+        let connection = Connection::open(path).map_err(|error| store_error(path, error))?;
+        connection.execute_batch(SCHEMA).map_err(|error| store_error(path, error))?;
+        Ok(Self { connection })
+    }
+
+    /// A store that is not saved anywhere.
+    pub fn in_memory() -> Result<Self> {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    /// `~/.local/share/bermuda/history.sqlite`.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| {
+            Path::new(&home)
+                .join(".local")
+                .join("share")
+                .join("bermuda")
+                .join("history.sqlite")
+        })
+    }
+
+    /// Record the snapshot and its holdings, unless its block is already recorded for its
+    /// smart wallet. Returns whether it was added.
+    pub fn append(&self, snapshot: &StoredSnapshot) -> Result<bool> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let added = transaction.execute(
+            "INSERT OR IGNORE INTO snapshots VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                snapshot.smart_wallet,
+                snapshot.block as i64,
+                snapshot.timestamp as i64,
+                snapshot.eth_price,
+                snapshot.wallet_eth,
+                snapshot.tokens_eth,
+                snapshot.short,
+                snapshot.long,
+                snapshot.total,
+                serde_json::to_string(&snapshot.equalize)?,
+            ],
+        )?;
+        if added > 0 {
+            for holding in snapshot.holdings.iter() {
+                transaction.execute(
+                    "INSERT INTO holdings VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        snapshot.smart_wallet,
+                        snapshot.block as i64,
+                        holding.token,
+                        holding.symbol,
+                        holding.balance,
+                        holding.usd_value,
+                        holding.eth_value,
+                    ],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(added > 0)
+    }
+
+    /// The snapshots taken between `from` and `to` included, of `smart_wallet` or of all of
+    /// them, oldest first.
+    pub fn list(&self, smart_wallet: Option<&str>, from: u64, to: u64) -> Result<Vec<StoredSnapshot>> {
+        let smart_wallet = smart_wallet.map(|smart_wallet| smart_wallet.trim_start_matches("0x").to_lowercase());
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM snapshots
+             WHERE (?1 IS NULL OR smart_wallet = ?1) AND timestamp BETWEEN ?2 AND ?3
+             ORDER BY timestamp, block",
+        )?;
+        // timestamps are stored as signed integers
+        let rows = statement.query_map(
+            params![smart_wallet, from.min(i64::MAX as u64) as i64, to.min(i64::MAX as u64) as i64],
+            StoredSnapshot::from_row,
+        )?;
+        let mut snapshots = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement = self.connection.prepare(
+            "SELECT token, symbol, balance, usd_value, eth_value FROM holdings
+             WHERE smart_wallet = ?1 AND block = ?2
+             ORDER BY token",
+        )?;
+        for snapshot in snapshots.iter_mut() {
+            let rows = statement.query_map(params![snapshot.smart_wallet, snapshot.block as i64], |row| {
+                Ok(StoredHolding {
+                    token: row.get(0)?,
+                    symbol: row.get(1)?,
+                    balance: row.get(2)?,
+                    usd_value: row.get(3)?,
+                    eth_value: row.get(4)?,
+                })
+            })?;
+            snapshot.holdings = rows.collect::<rusqlite::Result<_>>()?;
+        }
+        Ok(snapshots)
+    }
+
+    /// The number of recorded snapshots.
+    pub fn count(&self) -> Result<u64> {
+        let count: i64 = self
+            .connection
+            .query_row("SELECT COUNT(*) FROM snapshots", NO_PARAMS, |row| row.get(0))?;
+        Ok(count as u64)
+    }
+}
+
+fn store_error<E: std::fmt::Display>(path: &Path, error: E) -> BermudaError {
+    BermudaError::Store(format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Currency;
+    use test_case::test_case;
+
+    fn snapshot(smart_wallet: &str, block: u64, timestamp: u64) -> StoredSnapshot {
+        StoredSnapshot {
+            smart_wallet: smart_wallet.to_string(),
+            block,
+            timestamp,
+            eth_price: 2000.0,
+            wallet_eth: 1.0,
+            tokens_eth: 0.5,
+            short: 5.0,
+            long: 2.5,
+            total: 9.0,
+            equalize: Equalize {
+                eth_price: 2000.0,
+                flash_loan_currency: Currency::USDC,
+                flash_loan_value: 7500.0,
+                keep_usdc: 1500.0,
+                short_col_delta_usdc: 7500.0,
+                short_debt_delta_eth: 2.5,
+                long_col_delta_eth: 4.5,
+                long_debt_delta_usdc: 6000.0,
+            },
+            holdings: vec![StoredHolding {
+                token: String::from("6b175474e89094c44da98b954eedeac495271d0f"),
+                symbol: String::from("DAI"),
+                balance: 1000.0,
+                usd_value: 1000.0,
+                eth_value: 0.5,
+            }],
+        }
+    }
+
+    fn store() -> SnapshotStore {
+        let store = SnapshotStore::in_memory().unwrap();
+        for (smart_wallet, block, timestamp) in [("aa", 10, 1_000), ("aa", 11, 2_000), ("bb", 11, 2_000)].iter() {
+            assert!(store.append(&snapshot(smart_wallet, *block, *timestamp)).unwrap());
+        }
+        store
+    }
+
+    #[test]
+    fn append_and_list() {
+        let store = store();
+        // the same block of the same smart wallet is only recorded once, with its holdings
+        let mut again = snapshot("aa", 10, 1_000);
+        again.short = 6.0;
+        assert!(!store.append(&again).unwrap());
+        assert_eq!(store.count().unwrap(), 3);
+
+        let listed = store.list(Some("0xAA"), 0, u64::MAX).unwrap();
+        assert_eq!(listed[0].short, 5.0);
+        assert_eq!(listed[0].holdings, snapshot("aa", 10, 1_000).holdings);
+        assert_eq!(listed[0].equalize.flash_loan_currency, Currency::USDC);
+        assert_eq!(listed[0].equalize.long_debt_delta_usdc, 6000.0);
+
+        let mut empty = snapshot("cc", 12, 3_000);
+        empty.holdings.clear();
+        assert!(store.append(&empty).unwrap());
+        assert!(store.list(Some("cc"), 0, u64::MAX).unwrap()[0].holdings.is_empty());
+    }
+
+    #[test_case(Some("aa"), 0, u64::MAX => vec!["aa 10", "aa 11"] ; "one smart wallet")]
+    #[test_case(Some("0xBB"), 0, u64::MAX => vec!["bb 11"] ; "prefixed and uppercase")]
+    #[test_case(None, 1_500, 2_000 => vec!["aa 11", "bb 11"] ; "all smart wallets, to included")]
+    #[test_case(None, 1_000, 1_000 => vec!["aa 10"] ; "from included")]
+    #[test_case(None, 0, 999 => Vec::<&str>::new() ; "before the first snapshot")]
+    fn list_tests(smart_wallet: Option<&str>, from: u64, to: u64) -> Vec<String> {
+        let listed = store().list(smart_wallet, from, to).unwrap();
+        listed.iter().map(|snapshot| format!("{} {}", snapshot.smart_wallet, snapshot.block)).collect()
+    }
+
+    #[test]
+    fn csv_line_matches_the_header() {
+        let line = snapshot("aa", 10, 1_000).to_csv();
+        assert_eq!(line.split(',').count(), StoredSnapshot::csv_header().split(',').count());
+        assert!(line.starts_with("aa,10,1000,2000,1,0.5,5,2.5,9,USDC,7500,"));
+    }
+}
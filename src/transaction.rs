@@ -0,0 +1,360 @@
+use super::amount::Amount;
+use super::blockchain::BlockchainReader;
+use super::compound::USDC_DECIMALS;
+use super::decode::parse_address;
+use super::error::{BermudaError, Result};
+use super::multicall::{load_multicall3, MULTICALL3_ADDRESS};
+use super::smart_wallet::SmartWallet;
+use super::{Currency, Equalize, RebalanceCosts};
+use ethabi::{Address, Contract, Token, Uint};
+use rustc_hex::ToHex;
+use serde::{Serialize, Serializer};
+
+/// The Uniswap V3 `SwapRouter`.
+pub(crate) const SWAP_ROUTER_ADDRESS: &str = "E592427A0AEce92De3Edee1F18E0157C05861564";
+/// The fee tier of the USDC / WETH pool, 0.05%.
+pub const SWAP_FEE: u32 = 500;
+const VARIABLE_RATE: u64 = 2;
+
+/// The contracts a rebalance talks to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RebalanceContracts {
+    pub aave: Address,
+    pub compound: Address,
+    pub weth: Address,
+    pub usdc: Address,
+    pub swap_router: Address,
+    /// The Aave flash loan receiver. Its `executeOperation` must hand the borrowed asset to the
+    /// smart wallet, call the smart wallet with the `params` of the flash loan, then repay
+    /// the pool with what the smart wallet sent back.
+    pub flash_receiver: Address,
+}
+
+/// Who sends the transaction, and how.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionOptions {
+    pub from: Address,
+    pub gas: u64,
+    pub chain_id: u64,
+}
+
+/// One call made by the smart wallet.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Step {
+    pub description: String,
+    pub to: Address,
+    #[serde(serialize_with = "serialize_hex")]
+    pub data: Vec<u8>,
+}
+
+/// A transaction as expected by signing tools, every number being an hex quantity.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsignedTransaction {
+    pub from: Address,
+    pub to: Address,
+    pub value: Uint,
+    #[serde(serialize_with = "serialize_hex")]
+    pub data: Vec<u8>,
+    pub gas: Uint,
+    pub chain_id: Uint,
+}
+
+/// The rebalance transaction, with the calls it makes for review.
+#[derive(Serialize, Debug, Clone)]
+pub struct RebalanceTransaction {
+    pub steps: Vec<Step>,
+    pub transaction: UnsignedTransaction,
+}
+
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{}", data.to_hex::<String>()))
+}
+
+/// The raw amount of `value` tokens with `decimals` digits.
+fn raw_amount(value: f64, decimals: u8) -> Result<Uint> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(BermudaError::Input(format!("cannot rebalance a negative amount: {}", value)));
+    }
+    Ok(Amount::parse(&format!("{:.*}", decimals as usize, value), decimals)?.raw())
+}
+
+struct Contracts {
+    aave: Contract,
+    comet: Contract,
+    erc20: Contract,
+    router: Contract,
+}
+
+impl Contracts {
+    fn load() -> Result<Self> {
+        Ok(Self {
+            aave: Contract::load(&include_bytes!("abi/aave.abi")[..])?,
+            comet: Contract::load(&include_bytes!("abi/cUSDCv3.abi")[..])?,
+            erc20: Contract::load(&include_bytes!("abi/erc20.abi")[..])?,
+            router: Contract::load(&include_bytes!("abi/uniswap_v3_router.abi")[..])?,
+        })
+    }
+}
+
+fn step(description: String, to: &Address, contract: &Contract, name: &str, params: &[Token]) -> Result<Step> {
+    Ok(Step {
+        description,
+        to: *to,
+        data: contract.function(name)?.encode_input(params)?,
+    })
+}
+
+/// The `aggregate3` calldata running every step in order, reverting if any fails.
+fn encode_steps(steps: &[Step]) -> Result<Vec<u8>> {
+    let calls = steps
+        .iter()
+        .map(|step| Token::Tuple(vec![Token::Address(step.to), Token::Bool(false), Token::Bytes(step.data.clone())]))
+        .collect();
+    Ok(load_multicall3()?.function("aggregate3")?.encode_input(&[Token::Array(calls)])?)
+}
+
+/// Turn the `equalize` plan into a single transaction to the smart wallet, sent by its owner.
+///
+/// The smart wallet delegatecalls Multicall3 to make its calls itself. It flash borrows from
+/// Aave, and the flash loan receiver calls it back with the steps moving the loans, buying
+/// exactly `costs.flash_loan_repay` for at most `costs.swap_amount` and sending it to the
+/// receiver. The rest of the freed amount stays in the smart wallet.
+pub fn build_rebalance<R: BlockchainReader>(
+    equalize: &Equalize,
+    costs: &RebalanceCosts,
+    contracts: &RebalanceContracts,
+    smart_wallet: &SmartWallet<R>,
+    deadline: u64,
+    options: &TransactionOptions,
+) -> Result<RebalanceTransaction> {
+    let abi = Contracts::load()?;
+    let wallet = *smart_wallet.address();
+    let multicall = parse_address(MULTICALL3_ADDRESS)?;
+    let eth = |value: f64| raw_amount(value, 18);
+    let usd = |value: f64| raw_amount(value, USDC_DECIMALS);
+    let approve = |token: &Address, symbol: &str, spender: &Address, amount: Uint| {
+        step(
+            format!("Approve {:#x} to spend {} {}", spender, amount, symbol),
+            token,
+            &abi.erc20,
+            "approve",
+            &[Token::Address(*spender), Token::Uint(amount)],
+        )
+    };
+    let swap = |token_in: &Address, token_out: &Address, amount_out: Uint, max_in: Uint, description: String| {
+        step(
+            description,
+            &contracts.swap_router,
+            &abi.router,
+            "exactOutputSingle",
+            &[Token::Tuple(vec![
+                Token::Address(*token_in),
+                Token::Address(*token_out),
+                Token::Uint(Uint::from(SWAP_FEE)),
+                Token::Address(wallet),
+                Token::Uint(Uint::from(deadline)),
+                Token::Uint(amount_out),
+                Token::Uint(max_in),
+                Token::Uint(Uint::zero()),
+            ])],
+        )
+    };
+
+    let (flash_asset, flash_amount, steps) = match equalize.flash_loan_currency {
+        Currency::ETH => {
+            let repay = eth(equalize.short_debt_delta_eth)?;
+            let withdraw = usd(equalize.short_col_delta_usdc)?;
+            let supply = eth(equalize.long_col_delta_eth)?;
+            let borrow = usd(equalize.long_debt_delta_usdc)?;
+            let sell = usd(costs.swap_amount)?;
+            let buy = eth(costs.swap_min_received)?;
+            let steps = vec![
+                approve(&contracts.weth, "WETH", &contracts.aave, repay)?,
+                step(
+                    format!("Short (AAVE): repay {:.4} eth of debt", equalize.short_debt_delta_eth),
+                    &contracts.aave,
+                    &abi.aave,
+                    "repay",
+                    &[Token::Address(contracts.weth), Token::Uint(repay), Token::Uint(Uint::from(VARIABLE_RATE)), Token::Address(wallet)],
+                )?,
+                step(
+                    format!("Short (AAVE): withdraw {:.2} $ of collateral", equalize.short_col_delta_usdc),
+                    &contracts.aave,
+                    &abi.aave,
+                    "withdraw",
+                    &[Token::Address(contracts.usdc), Token::Uint(withdraw), Token::Address(wallet)],
+                )?,
+                approve(&contracts.weth, "WETH", &contracts.compound, supply)?,
+                step(
+                    format!("Long (Compound): supply {:.4} eth of collateral", equalize.long_col_delta_eth),
+                    &contracts.compound,
+                    &abi.comet,
+                    "supply",
+                    &[Token::Address(contracts.weth), Token::Uint(supply)],
+                )?,
+                step(
+                    format!("Long (Compound): borrow {:.2} $", equalize.long_debt_delta_usdc),
+                    &contracts.compound,
+                    &abi.comet,
+                    "withdraw",
+                    &[Token::Address(contracts.usdc), Token::Uint(borrow)],
+                )?,
+                approve(&contracts.usdc, "USDC", &contracts.swap_router, sell)?,
+                swap(&contracts.usdc, &contracts.weth, buy, sell, format!(
+                    "Buy {:.4} eth for at most {:.2} $", costs.swap_min_received, costs.swap_amount
+                ))?,
+                step(
+                    format!("Send {:.4} eth to the flash loan receiver", costs.flash_loan_repay),
+                    &contracts.weth,
+                    &abi.erc20,
+                    "transfer",
+                    &[Token::Address(contracts.flash_receiver), Token::Uint(eth(costs.flash_loan_repay)?)],
+                )?,
+            ];
+            (contracts.weth, eth(equalize.flash_loan_value)?, steps)
+        }
+        Currency::USDC => {
+            let repay = usd(equalize.long_debt_delta_usdc)?;
+            let withdraw = eth(equalize.long_col_delta_eth)?;
+            let deposit = usd(equalize.short_col_delta_usdc)?;
+            let borrow = eth(equalize.short_debt_delta_eth)?;
+            let sell = eth(costs.swap_amount)?;
+            let buy = usd(costs.swap_min_received)?;
+            let steps = vec![
+                approve(&contracts.usdc, "USDC", &contracts.compound, repay)?,
+                step(
+                    format!("Long (Compound): repay {:.2} $ of debt", equalize.long_debt_delta_usdc),
+                    &contracts.compound,
+                    &abi.comet,
+                    "supply",
+                    &[Token::Address(contracts.usdc), Token::Uint(repay)],
+                )?,
+                step(
+                    format!("Long (Compound): withdraw {:.4} eth of collateral", equalize.long_col_delta_eth),
+                    &contracts.compound,
+                    &abi.comet,
+                    "withdraw",
+                    &[Token::Address(contracts.weth), Token::Uint(withdraw)],
+                )?,
+                approve(&contracts.usdc, "USDC", &contracts.aave, deposit)?,
+                step(
+                    format!("Short (AAVE): add {:.2} $ of collateral", equalize.short_col_delta_usdc),
+                    &contracts.aave,
+                    &abi.aave,
+                    "deposit",
+                    &[Token::Address(contracts.usdc), Token::Uint(deposit), Token::Address(wallet), Token::Uint(Uint::zero())],
+                )?,
+                step(
+                    format!("Short (AAVE): borrow {:.4} eth", equalize.short_debt_delta_eth),
+                    &contracts.aave,
+                    &abi.aave,
+                    "borrow",
+                    &[Token::Address(contracts.weth), Token::Uint(borrow), Token::Uint(Uint::from(VARIABLE_RATE)), Token::Uint(Uint::zero()), Token::Address(wallet)],
+                )?,
+                approve(&contracts.weth, "WETH", &contracts.swap_router, sell)?,
+                swap(&contracts.weth, &contracts.usdc, buy, sell, format!(
+                    "Buy {:.2} $ for at most {:.4} eth", costs.swap_min_received, costs.swap_amount
+                ))?,
+                step(
+                    format!("Send {:.2} $ to the flash loan receiver", costs.flash_loan_repay),
+                    &contracts.usdc,
+                    &abi.erc20,
+                    "transfer",
+                    &[Token::Address(contracts.flash_receiver), Token::Uint(usd(costs.flash_loan_repay)?)],
+                )?,
+            ];
+            (contracts.usdc, usd(equalize.flash_loan_value)?, steps)
+        }
+    };
+
+    // what the receiver calls the smart wallet with during the flash loan
+    let callback = smart_wallet.encode_execute(&multicall, encode_steps(&steps)?)?;
+    let flash_loan = step(
+        format!("Flash borrow {} of {:#x}", flash_amount, flash_asset),
+        &contracts.aave,
+        &abi.aave,
+        "flashLoan",
+        &[
+            Token::Address(contracts.flash_receiver),
+            Token::Array(vec![Token::Address(flash_asset)]),
+            Token::Array(vec![Token::Uint(flash_amount)]),
+            Token::Array(vec![Token::Uint(Uint::zero())]),
+            Token::Address(wallet),
+            Token::Bytes(callback),
+            Token::Uint(Uint::zero()),
+        ],
+    )?;
+    let data = smart_wallet.encode_execute(&multicall, encode_steps(std::slice::from_ref(&flash_loan))?)?;
+
+    Ok(RebalanceTransaction {
+        steps: std::iter::once(flash_loan).chain(steps).collect(),
+        transaction: UnsignedTransaction {
+            from: options.from,
+            to: wallet,
+            value: Uint::zero(),
+            data,
+            gas: Uint::from(options.gas),
+            chain_id: Uint::from(options.chain_id),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{initialize_bermuda, rebalance_costs, Loan, MockBlockchainReader, StrategyParams};
+    use test_case::test_case;
+
+    #[test_case(Loan { collateral: 12.0, debt: 8.0 }, Loan { collateral: 3.0, debt: 2.0 } => Currency::ETH ; "eth flash loan")]
+    #[test_case(Loan { collateral: 3.0, debt: 2.0 }, Loan { collateral: 12.0, debt: 8.0 } => Currency::USDC ; "usdc flash loan")]
+    fn build_rebalance_wraps_the_steps(short: Loan, long: Loan) -> Currency {
+        let equalize = initialize_bermuda(short, long, 2000.0, &StrategyParams::default()).unwrap();
+        let costs = rebalance_costs(&equalize, 0.0009, 0.01, 0.0).unwrap();
+        let address = |n: u64| Address::from_low_u64_be(n);
+        let contracts = RebalanceContracts {
+            aave: address(1),
+            compound: address(2),
+            weth: address(3),
+            usdc: address(4),
+            swap_router: address(5),
+            flash_receiver: address(6),
+        };
+        let reader = MockBlockchainReader::new();
+        let smart_wallet = SmartWallet::new(&reader, "0000000000000000000000000000000000000007").unwrap();
+
+        let options = TransactionOptions { from: address(8), gas: 1_200_000, chain_id: 1 };
+        let rebalance = build_rebalance(&equalize, &costs, &contracts, &smart_wallet, 1_700_000_000, &options).unwrap();
+        assert_eq!(rebalance.steps.len(), 10);
+        assert_eq!(rebalance.steps[0].to, contracts.aave);
+        assert_eq!(rebalance.transaction.to, address(7));
+        // execute(address,bytes)
+        assert_eq!(rebalance.transaction.data[..4], [0x1c, 0xff, 0x79, 0xcd]);
+
+        let json = serde_json::to_value(&rebalance.transaction).unwrap();
+        assert_eq!(json["chainId"], "0x1");
+        assert_eq!(json["from"], "0x0000000000000000000000000000000000000008");
+
+        // the swap buys the whole repay, selling less than the rebalance frees
+        assert!(costs.swap_min_received >= costs.flash_loan_repay);
+        let (freed, decimals, repay_decimals) = match equalize.flash_loan_currency {
+            Currency::ETH => (equalize.short_col_delta_usdc + equalize.long_debt_delta_usdc, USDC_DECIMALS, 18),
+            Currency::USDC => (equalize.long_col_delta_eth + equalize.short_debt_delta_eth, 18, USDC_DECIMALS),
+        };
+        assert!(costs.swap_amount < freed);
+        let router = Contract::load(&include_bytes!("abi/uniswap_v3_router.abi")[..]).unwrap();
+        let swap = &rebalance.steps[8];
+        assert_eq!(swap.to, contracts.swap_router);
+        let params = router.function("exactOutputSingle").unwrap().decode_input(&swap.data[4..]).unwrap();
+        let params = match &params[0] {
+            Token::Tuple(params) => params.clone(),
+            token => panic!("unexpected {:?}", token),
+        };
+        assert_eq!(params[5], Token::Uint(raw_amount(costs.flash_loan_repay, repay_decimals).unwrap()));
+        assert_eq!(params[6], Token::Uint(raw_amount(costs.swap_amount, decimals).unwrap()));
+
+        let negative = Equalize { short_debt_delta_eth: -1.0, ..equalize };
+        assert!(build_rebalance(&negative, &costs, &contracts, &smart_wallet, 0, &options).is_err());
+        equalize.flash_loan_currency
+    }
+}
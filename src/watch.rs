@@ -0,0 +1,90 @@
+use super::portfolio::PortfolioSnapshot;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// What `watch` follows from one refresh to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchValues {
+    pub block: u64,
+    pub price: f64,
+    pub short: f64,
+    pub long: f64,
+    pub total: f64,
+    /// None when the leg has no debt.
+    pub short_health_factor: Option<f64>,
+    pub long_health_factor: Option<f64>,
+}
+
+impl From<&PortfolioSnapshot> for WatchValues {
+    fn from(snapshot: &PortfolioSnapshot) -> Self {
+        Self {
+            block: snapshot.block,
+            price: snapshot.eth_price,
+            short: snapshot.short,
+            long: snapshot.long,
+            total: snapshot.total,
+            short_health_factor: snapshot.short_account.health_factor,
+            long_health_factor: snapshot.long_account.health_factor(),
+        }
+    }
+}
+
+/// The values as terminal lines. Those that moved since `previous` are followed by the change,
+/// in green when they went up and in red when they went down.
+pub fn render_watch(current: &WatchValues, previous: Option<&WatchValues>) -> String {
+    let line = |name: &str, unit: &str, value: Option<f64>, before: Option<f64>| {
+        let value = match value {
+            Some(value) => value,
+            None => return format!("{:<22}no debt\n", name),
+        };
+        let change = match before {
+            Some(before) if format!("{:.2}", value) != format!("{:.2}", before) => {
+                let color = if value > before { GREEN } else { RED };
+                format!("  {}{:+.2}{}", color, value - before, RESET)
+            }
+            _ => String::new(),
+        };
+        format!("{:<22}{:>12.2} {}{}\n", name, value, unit, change)
+    };
+    let before = |value: fn(&WatchValues) -> Option<f64>| previous.and_then(value);
+
+    let mut view = format!("block: {}\n\n", current.block);
+    view.push_str(&line("eth price", "$", Some(current.price), before(|values| Some(values.price))));
+    view.push_str(&line("Short", "eth", Some(current.short), before(|values| Some(values.short))));
+    view.push_str(&line("Long", "eth", Some(current.long), before(|values| Some(values.long))));
+    view.push_str(&line("Total", "eth", Some(current.total), before(|values| Some(values.total))));
+    view.push_str(&line("Short health factor", "", current.short_health_factor, before(|values| values.short_health_factor)));
+    view.push_str(&line("Long health factor", "", current.long_health_factor, before(|values| values.long_health_factor)));
+    view
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: WatchValues = WatchValues {
+        block: 16_000_000,
+        price: 2000.0,
+        short: 10.0,
+        long: 5.0,
+        total: 16.0,
+        short_health_factor: Some(1.5),
+        long_health_factor: None,
+    };
+
+    #[test]
+    fn render_watch_tests() {
+        let first = render_watch(&VALUES, None);
+        assert!(!first.contains(GREEN) && !first.contains(RED));
+        assert!(first.contains("no debt"));
+        assert_eq!(render_watch(&VALUES, Some(&VALUES)), first);
+
+        let next = WatchValues { block: 16_000_001, price: 2100.0, short: 9.5, ..VALUES };
+        let view = render_watch(&next, Some(&VALUES));
+        assert!(view.contains(&format!("{}+100.00{}", GREEN, RESET)));
+        assert!(view.contains(&format!("{}-0.50{}", RED, RESET)));
+        assert_eq!(view.matches(RESET).count(), 2);
+    }
+}
@@ -0,0 +1,210 @@
+use super::amount::Amount;
+use super::blockchain::BlockchainReader;
+use super::chainlink::{Chainlink, OracleLimits};
+use super::decode::parse_address;
+use super::erc20::{TokenInfo, ERC20};
+use super::error::{BermudaError, Result};
+use super::multicall::Batch;
+use ethabi::Address;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DAI_ADDRESS: &str = "6b175474e89094c44da98b954eedeac495271d0f";
+pub const USDC_ADDRESS: &str = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+
+/// How the price of a watched token is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pricing {
+    /// Worth 1 $.
+    Stablecoin,
+    /// Worth 1 eth, like WETH.
+    Ether,
+    /// Quoted in $ by a Chainlink feed, like WBTC with BTC/USD.
+    UsdFeed(Address),
+    /// Quoted in eth by a Chainlink feed, like stETH with stETH/ETH.
+    EthFeed(Address),
+}
+
+/// An ERC20 token of the owner wallet that is part of the portfolio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchedToken {
+    pub address: Address,
+    pub pricing: Pricing,
+}
+
+impl WatchedToken {
+    /// DAI and USDC, counted as 1 $ each.
+    pub fn defaults() -> Vec<WatchedToken> {
+        [DAI_ADDRESS, USDC_ADDRESS]
+            .iter()
+            .map(|address| WatchedToken {
+                address: address.parse().unwrap(),
+                pricing: Pricing::Stablecoin,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for WatchedToken {
+    type Err = BermudaError;
+
+    /// Parse `<token>:stable`, `<token>:eth`, `<token>:usd=<feed>` or `<token>:eth=<feed>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            BermudaError::Input(format!(
+                "invalid token {}, expected <token>:stable, <token>:eth, <token>:usd=<feed> or <token>:eth=<feed>",
+                s
+            ))
+        };
+        let (address, pricing) = match s.find(':') {
+            Some(colon) => (&s[..colon], &s[colon + 1..]),
+            None => return Err(invalid()),
+        };
+        let pricing = match pricing {
+            "stable" => Pricing::Stablecoin,
+            "eth" => Pricing::Ether,
+            _ => match pricing.find('=') {
+                Some(equal) if &pricing[..equal] == "usd" => {
+                    Pricing::UsdFeed(parse_address(&pricing[equal + 1..])?)
+                }
+                Some(equal) if &pricing[..equal] == "eth" => {
+                    Pricing::EthFeed(parse_address(&pricing[equal + 1..])?)
+                }
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(WatchedToken {
+            address: parse_address(address)?,
+            pricing,
+        })
+    }
+}
+
+impl fmt::Display for WatchedToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pricing {
+            Pricing::Stablecoin => write!(f, "{:#x}:stable", self.address),
+            Pricing::Ether => write!(f, "{:#x}:eth", self.address),
+            Pricing::UsdFeed(feed) => write!(f, "{:#x}:usd={:#x}", self.address, feed),
+            Pricing::EthFeed(feed) => write!(f, "{:#x}:eth={:#x}", self.address, feed),
+        }
+    }
+}
+
+/// The balance of a watched token, priced in $ and eth.
+#[derive(Serialize, Debug, Clone)]
+pub struct Holding {
+    pub token: TokenInfo,
+    pub balance: Amount,
+    pub usd_price: f64,
+    pub usd_value: f64,
+    pub eth_value: f64,
+}
+
+/// The holdings of `owner` in each watched token, at the given eth price in $. The feeds
+/// pricing the tokens are checked against `limits`.
+pub async fn get_holdings<R: BlockchainReader>(
+    blockchain_reader: &R,
+    owner: &Address,
+    tokens: &[WatchedToken],
+    eth_price: f64,
+    limits: OracleLimits,
+) -> Result<Vec<Holding>> {
+    let erc20s = tokens
+        .iter()
+        .map(|watched| ERC20::new(blockchain_reader, watched.address))
+        .collect::<Result<Vec<_>>>()?;
+    // the balances and descriptions of every token in one round-trip
+    let mut batch = Batch::new();
+    let mut calls = Vec::new();
+    for erc20 in erc20s.iter() {
+        calls.push((erc20.add_balance(&mut batch, owner)?, erc20.add_info(&mut batch)?));
+    }
+    let results = blockchain_reader.call_batch(&batch).await?;
+
+    let mut holdings = Vec::new();
+    for ((watched, erc20), (balance_call, info_calls)) in tokens.iter().zip(erc20s.iter()).zip(calls) {
+        erc20.cache_info(&info_calls, &results);
+        let usd_price = match watched.pricing {
+            Pricing::Stablecoin => 1.0,
+            Pricing::Ether => eth_price,
+            Pricing::UsdFeed(feed) => {
+                Chainlink::at(blockchain_reader, feed)?.with_limits(limits).get_answer().await?
+            }
+            Pricing::EthFeed(feed) => {
+                Chainlink::at(blockchain_reader, feed)?.with_limits(limits).get_answer().await? * eth_price
+            }
+        };
+        let balance = erc20.decode_balance(&results[balance_call].clone()?).await?;
+        let usd_value = balance.to_f64() * usd_price;
+        holdings.push(Holding {
+            token: erc20.get_info().await?,
+            balance,
+            usd_price,
+            usd_value,
+            eth_value: usd_value / eth_price,
+        });
+    }
+    Ok(holdings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockBlockchainReader;
+    use ethabi::{Contract, Token, Uint};
+    use test_case::test_case;
+
+    #[test_case("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2:eth" ; "ether")]
+    #[test_case("0x6b175474e89094c44da98b954eedeac495271d0f:stable" ; "stablecoin")]
+    #[test_case("0x2260fac5e5542a773aa44fbcfedf7c193bc2c599:usd=0xf4030086522a5beea4988f8ca5b36dbc97bee88c" ; "usd feed")]
+    #[test_case("0xae7ab96520de3a18e5e111b5eaab095312d7fe84:eth=0x86392dc19c0b719886221c78ab11eb8cf5c52812" ; "eth feed")]
+    fn watched_token_round_trip(input: &str) {
+        let token: WatchedToken = input.parse().unwrap();
+        assert_eq!(token.to_string(), input.to_lowercase());
+    }
+
+    #[test_case("0x6b175474e89094c44da98b954eedeac495271d0f" ; "no pricing")]
+    #[test_case("0x6b175474e89094c44da98b954eedeac495271d0f:btc" ; "unknown pricing")]
+    #[test_case("dai:stable" ; "bad address")]
+    fn watched_token_errors(input: &str) {
+        assert!(input.parse::<WatchedToken>().is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_token_feed_is_rejected() {
+        let wbtc: Address = "2260fac5e5542a773aa44fbcfedf7c193bc2c599".parse().unwrap();
+        let feed: Address = "f4030086522a5beea4988f8ca5b36dbc97bee88c".parse().unwrap();
+        let owner = Address::from_low_u64_be(1);
+        let erc20 = Contract::load(&include_bytes!("abi/erc20.abi")[..]).unwrap();
+        let chainlink = Contract::load(&include_bytes!("abi/chainlink.abi")[..]).unwrap();
+        let mut reader = MockBlockchainReader::new();
+        reader.mock_call(&erc20, &wbtc, "decimals", &[], &[Token::Uint(Uint::from(8))]).unwrap();
+        reader.mock_call(&erc20, &wbtc, "symbol", &[], &[Token::String(String::from("WBTC"))]).unwrap();
+        reader.mock_call(&erc20, &wbtc, "name", &[], &[Token::String(String::from("Wrapped BTC"))]).unwrap();
+        reader
+            .mock_call(&erc20, &wbtc, "balanceOf", &[Token::Address(owner)], &[Token::Uint(Uint::exp10(8) / 2)])
+            .unwrap();
+        let updated_at = Uint::from(1_700_000_000u64);
+        let round = [
+            Token::Uint(Uint::one()),
+            Token::Int(Uint::exp10(8) * 40_000),
+            Token::Uint(updated_at),
+            Token::Uint(updated_at),
+            Token::Uint(Uint::one()),
+        ];
+        reader.mock_call(&chainlink, &feed, "latestRoundData", &[], &round).unwrap();
+        reader.mock_call(&chainlink, &feed, "decimals", &[], &[Token::Uint(Uint::from(8))]).unwrap();
+        let tokens = [WatchedToken { address: wbtc, pricing: Pricing::UsdFeed(feed) }];
+
+        reader.mock_timestamp(1_700_000_000 + 3600);
+        let holdings = get_holdings(&reader, &owner, &tokens, 2000.0, OracleLimits::daily()).await.unwrap();
+        assert_eq!(holdings[0].usd_value, 20_000.0);
+        assert_eq!(holdings[0].eth_value, 10.0);
+
+        reader.mock_timestamp(1_700_000_000 + 2 * 86_400);
+        let error = get_holdings(&reader, &owner, &tokens, 2000.0, OracleLimits::daily()).await;
+        assert!(matches!(error, Err(BermudaError::StaleOracle { .. })));
+    }
+}